@@ -1,85 +1,242 @@
 use std::{
-    io::{Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
+    os::unix::net::UnixStream,
+    path::Path,
     sync::{Arc, RwLock},
-    time::Instant,
+    time::Duration,
 };
 
-use lib::Toaster;
-
-fn daemon() -> UnixListener {
-    let daemon_res = UnixListener::bind("/tmp/toaster.sock");
+use lib::{
+    args::Arguments,
+    log_query::LoggedEntry,
+    paths::Paths,
+    protocol::{
+        accept_handshake, read_frame, write_frame, DaemonError, FrameWriter, OutputStream,
+        ProtocolError, Request, Response,
+    },
+    socket::{self, current_uid, peer_uid},
+    Toaster,
+};
 
-    let daemon = match daemon_res {
-        Ok(d) => d,
-        Err(_) => {
-            let res = std::fs::remove_file("/tmp/toaster.sock");
-            if res.is_err() {
-                panic!("Failed to remove socket file.");
-            }
+fn main() {
+    let mut args = Arguments::new();
+    let (socket_flag, config_flag) = args.take_global_options();
 
-            UnixListener::bind("/tmp/toaster.sock").unwrap()
+    let paths = match Paths::resolve(config_flag) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let toaster = match Toaster::new(paths) {
+        Ok(toaster) => Arc::new(RwLock::new(toaster)),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     };
-
-    return daemon;
-}
-
-fn main() {
-    let toaster = Arc::new(RwLock::new(Toaster::new()));
-    let mut last_flush = Instant::now();
     std::thread::sleep(std::time::Duration::from_millis(100));
 
+    let socket_path = {
+        let settings = &toaster.read().unwrap().settings;
+        Arc::new(socket::socket_path(socket_flag, settings.socket.as_deref()))
+    };
+    // Bind before starting systems so a second daemon bails out before running anything.
+    let daemon = match socket::bind(&socket_path) {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", socket_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    println!("Listening on {}", socket_path.display());
+
     {
         toaster.write().unwrap().start_systems();
     }
 
-    let daemon = daemon();
-
     daemon
         .set_nonblocking(false)
         .expect("Failed to set blocking");
 
     for stream in daemon.incoming() {
-        if last_flush.elapsed().as_secs() > 45 {
-            println!("Auto-Flushing output...");
-            toaster.write().unwrap().flush_output();
-            last_flush = Instant::now();
-        }
         let stream: UnixStream = stream.unwrap();
         let toaster = Arc::clone(&toaster);
+        let socket_path = Arc::clone(&socket_path);
         std::thread::spawn(move || {
-            handle_client(stream, toaster);
+            handle_client(stream, toaster, &socket_path);
         });
     }
 }
 
-fn handle_client(mut stream: UnixStream, toaster: Arc<RwLock<Toaster>>) {
-    let mut buf = [0; 20];
-    let len = stream.read(&mut buf).unwrap();
-
-    let string = String::from_utf8_lossy(&buf.to_vec().as_slice()[..len]).to_string();
-    println!("Received command: {}", string);
-    let str = string.as_str();
-
-    match str {
-        "reload" => {
-            println!("Reloading...");
-            toaster.write().unwrap().reload();
+fn handle_client(mut stream: UnixStream, toaster: Arc<RwLock<Toaster>>, socket_path: &Path) {
+    match peer_uid(&stream) {
+        Ok(uid) if uid == current_uid() => {}
+        Ok(uid) => {
+            eprintln!("Rejected client: connection from uid {}", uid);
+            // Read the client's hello first so it's listening for the refusal.
+            let _ = read_frame::<_, Request>(&mut stream);
+            let _ = write_frame(&mut stream, &Response::Error(DaemonError::PermissionDenied));
+            return;
         }
-        "flush" => {
-            println!("Flushing output...");
-            toaster.write().unwrap().flush_output();
-            println!("Flushed output.");
-            stream.write(b"ok").unwrap();
+        Err(e) => {
+            eprintln!("Rejected client: couldn't check its credentials: {}", e);
+            return;
         }
-        "ping" => {
-            stream.write(b"pong").unwrap();
+    }
+
+    if let Err(e) = accept_handshake(&mut stream) {
+        eprintln!("Rejected client: {}", e);
+        return;
+    }
+
+    loop {
+        let request: Request = match read_frame(&mut stream) {
+            Ok(request) => request,
+            Err(ProtocolError::Closed) => return,
+            Err(e) => {
+                eprintln!("Failed to read request: {}", e);
+                let error = DaemonError::Failed(format!("Bad request: {}", e));
+                let _ = write_frame(&mut stream, &Response::Error(error));
+                return;
+            }
+        };
+        println!("Received command: {:?}", request);
+
+        let response = match request {
+            Request::Hello { .. } => Response::Error(DaemonError::Failed(
+                "Handshake was already completed.".to_string(),
+            )),
+            Request::Reload => {
+                println!("Reloading...");
+                let result = toaster.write().unwrap().reload();
+                match result {
+                    Ok(summary) => {
+                        println!("Reloaded: {}", summary);
+                        Response::Reloaded(summary)
+                    }
+                    Err(e) => {
+                        eprintln!("Reload failed: {}", e);
+                        Response::Error(DaemonError::Failed(format!("Reload failed: {}", e)))
+                    }
+                }
+            }
+            Request::Flush => {
+                println!("Flushing output...");
+                let result = toaster.read().unwrap().flush_output();
+                match result {
+                    Ok(report) => {
+                        println!("Flushed {}.", report);
+                        Response::Flushed(report)
+                    }
+                    Err(e) => {
+                        eprintln!("Flush failed: {}", e);
+                        Response::Error(DaemonError::Failed(e))
+                    }
+                }
+            }
+            Request::Ping => Response::Pong,
+            Request::Start { system } => match toaster.write().unwrap().start_system(&system) {
+                Ok(()) => {
+                    println!("Started system '{}'.", system);
+                    Response::Ok
+                }
+                Err(e) => Response::Error(DaemonError::Failed(e)),
+            },
+            Request::Stop { system } => match toaster.write().unwrap().stop_system(&system) {
+                Ok(()) => {
+                    println!("Stopping system '{}'...", system);
+                    Response::Ok
+                }
+                Err(e) => Response::Error(DaemonError::Failed(e)),
+            },
+            Request::Shutdown => {
+                println!("Shutting down...");
+                let mut toaster = toaster.write().unwrap();
+                let grace = Duration::from_secs(toaster.settings.shutdown_timeout);
+                let killed = toaster.shutdown(grace);
+
+                let _ = write_frame(&mut stream, &Response::ShuttingDown { killed });
+                if let Err(e) = std::fs::remove_file(socket_path) {
+                    eprintln!("Failed to remove socket file: {}", e);
+                }
+                println!("Goodbye.");
+                std::process::exit(0);
+            }
+            Request::Run { command, args } => run_command(&stream, &toaster, &command, &args),
+            Request::Follow => {
+                follow_logs(&mut stream, &toaster);
+                return;
+            }
+            Request::Help { command } => match toaster.read().unwrap().get_command(&command) {
+                Some(builder) => Response::Help(builder.usage()),
+                None => Response::Error(DaemonError::CommandNotFound(command)),
+            },
+            Request::List => Response::List(toaster.read().unwrap().listing()),
+            Request::Status => Response::Status(toaster.read().unwrap().status()),
+        };
+
+        if let Err(e) = write_frame(&mut stream, &response) {
+            eprintln!("Failed to reply to client: {}", e);
+            return;
         }
-        _ => {
-            let msg = format!("Invalid command: {}", string);
-            println!("Bad command got from client: {}", msg);
-            stream.write(msg.as_bytes()).unwrap();
+    }
+}
+
+/// Runs a command, streaming its output to the client.
+/// Returns the final response: the exit code, or why the command couldn't run.
+fn run_command(
+    stream: &UnixStream,
+    toaster: &Arc<RwLock<Toaster>>,
+    name: &str,
+    args: &[String],
+) -> Response {
+    let prepared = toaster.read().unwrap().prepare_command(name, args);
+    let (command, args) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => return Response::Error(e),
+    };
+
+    let writers = stream.try_clone().and_then(|out| Ok((out, stream.try_clone()?)));
+    let (out, err) = match writers {
+        Ok(writers) => writers,
+        Err(e) => return Response::Error(DaemonError::Failed(e.to_string())),
+    };
+    let mut out = FrameWriter::new(out, OutputStream::Stdout);
+    let mut err = FrameWriter::new(err, OutputStream::Stderr);
+
+    println!("Running command: {}", name);
+    match command.run(&args, &mut out, &mut err) {
+        Ok(code) => Response::Exit(code),
+        Err(e) => Response::Error(DaemonError::Failed(format!(
+            "Failed to stream output: {}",
+            e
+        ))),
+    }
+}
+
+/// Streams every entry queued from now on to the client, starting with the ones still waiting
+/// to be written. Returns once the client goes away.
+fn follow_logs(stream: &mut UnixStream, toaster: &Arc<RwLock<Toaster>>) {
+    let (queued, receiver, format) = {
+        let toaster = toaster.read().unwrap();
+        let (queued, receiver) = toaster.output_queue.follow();
+        (queued, receiver, toaster.settings.logs.format)
+    };
+
+    let queued = queued
+        .iter()
+        .flat_map(|(secs, entry)| LoggedEntry::from_entry(*secs, entry, format))
+        .collect();
+    if write_frame(stream, &Response::Following(queued)).is_err() {
+        return;
+    }
+
+    for (secs, entry) in receiver {
+        for entry in LoggedEntry::from_entry(secs, &entry, format) {
+            if write_frame(stream, &Response::Logged(entry)).is_err() {
+                return;
+            }
         }
     }
 }
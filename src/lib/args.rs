@@ -0,0 +1,70 @@
+use std::env;
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Arguments {
+    arguments: Vec<String>,
+    dir: Option<String>,
+    iterator: std::iter::Peekable<std::vec::IntoIter<String>>,
+}
+
+impl Arguments {
+    pub fn new() -> Self {
+        let args = env::args().collect::<Vec<String>>();
+        let mut iterator = args.to_owned().into_iter().peekable();
+        let path = iterator.next();
+
+        let sself = Self {
+            arguments: args,
+            dir: path,
+            iterator,
+        };
+
+        sself
+    }
+
+    pub fn current(&mut self) -> Option<&String> {
+        self.iterator.peek()
+    }
+
+    pub fn next(&mut self) -> Option<String> {
+        self.iterator.next()
+    }
+
+    pub fn peek(&mut self) -> Option<&String> {
+        self.iterator.peek()
+    }
+
+    pub fn has_next(&mut self) -> bool {
+        self.iterator.peek().is_some()
+    }
+
+    /// Takes `--name value` or `--name=value` if it's the next argument, returning the value.
+    pub fn take_option(&mut self, name: &str) -> Option<String> {
+        let next = self.iterator.peek()?;
+        if next == name {
+            self.iterator.next();
+            return self.iterator.next();
+        }
+
+        let value = next.strip_prefix(name)?.strip_prefix('=')?.to_string();
+        self.iterator.next();
+        Some(value)
+    }
+
+    /// Takes the `--socket` and `--config` options both binaries accept before anything else,
+    /// in any order, returning `(socket, config)`. The last of each wins.
+    pub fn take_global_options(&mut self) -> (Option<String>, Option<String>) {
+        let mut socket = None;
+        let mut config = None;
+        loop {
+            if let Some(value) = self.take_option("--socket") {
+                socket = Some(value);
+            } else if let Some(value) = self.take_option("--config") {
+                config = Some(value);
+            } else {
+                return (socket, config);
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A one-way flag that can be waited on.
+/// Cloning shares the flag, so any clone can cancel every other.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the flag and wakes everyone waiting on it.
+    pub fn cancel(&self) {
+        let (cancelled, condvar) = &*self.inner;
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Blocks until the token is cancelled.
+    pub fn wait(&self) {
+        let (cancelled, condvar) = &*self.inner;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            cancelled = condvar.wait(cancelled).unwrap();
+        }
+    }
+
+    /// Blocks for up to `timeout`, returning early if the token is cancelled.
+    /// Returns whether the token was cancelled.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (cancelled, condvar) = &*self.inner;
+        let mut cancelled = cancelled.lock().unwrap();
+
+        while !*cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            cancelled = condvar.wait_timeout(cancelled, deadline - now).unwrap().0;
+        }
+
+        *cancelled
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The type an argument's value must have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl ArgType {
+    /// Checks that `value` is of this type, returning it in a normalized form.
+    pub fn check(&self, value: &str) -> Result<String, String> {
+        let invalid = || format!("'{}' isn't a valid {}", value, self);
+        match self {
+            ArgType::String => Ok(value.to_string()),
+            ArgType::Int => value
+                .parse::<i64>()
+                .map(|v| v.to_string())
+                .map_err(|_| invalid()),
+            ArgType::Float => value
+                .parse::<f64>()
+                .map(|v| v.to_string())
+                .map_err(|_| invalid()),
+            ArgType::Bool => match value.to_lowercase().as_str() {
+                "true" | "yes" | "1" => Ok("true".to_string()),
+                "false" | "no" | "0" => Ok("false".to_string()),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl Display for ArgType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgType::String => write!(f, "string"),
+            ArgType::Int => write!(f, "int"),
+            ArgType::Float => write!(f, "float"),
+            ArgType::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+/// An argument a command accepts as `--name value`, available to its stages as `${args.name}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArgSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ArgType,
+    pub required: bool,
+    /// The only values allowed, if not empty.
+    pub choices: Vec<String>,
+    pub default: Option<String>,
+    pub description: String,
+}
+
+impl ArgSpec {
+    /// How the argument is written in usage, e.g. `--env <dev|prod>`.
+    pub fn synopsis(&self) -> String {
+        let value = match (self.kind, self.choices.is_empty()) {
+            (ArgType::Bool, _) => return format!("--{}", self.name),
+            (_, false) => self.choices.join("|"),
+            (kind, true) => kind.to_string(),
+        };
+        format!("--{} <{}>", self.name, value)
+    }
+
+    /// Checks a value against the argument's type and choices.
+    pub fn check(&self, value: &str) -> Result<String, String> {
+        let value = self
+            .kind
+            .check(value)
+            .map_err(|e| format!("--{}: {}", self.name, e))?;
+        if !self.choices.is_empty() && !self.choices.contains(&value) {
+            return Err(format!(
+                "--{}: '{}' isn't one of {}",
+                self.name,
+                value,
+                self.choices.join(", ")
+            ));
+        }
+        Ok(value)
+    }
+}
+
+/// Arguments given to `toaster run`, split into the declared ones and the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedArgs {
+    /// Every declared argument, with its default or an empty string if it wasn't given.
+    pub named: BTreeMap<String, String>,
+    /// Everything else, handed to stages as `$1`, `$2`, ...
+    pub positional: Vec<String>,
+}
+
+/// Matches `args` against `specs`.
+///
+/// Declared arguments are written `--name value` or `--name=value`, and bool arguments can
+/// be given as just `--name`. Anything after `--` is positional.
+/// A command that declares no arguments gets all of them as positional, as they were given.
+pub fn parse_args(specs: &[ArgSpec], args: &[String]) -> Result<ParsedArgs, String> {
+    let mut parsed = ParsedArgs::default();
+    if specs.is_empty() {
+        parsed.positional = args.to_vec();
+        return Ok(parsed);
+    }
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            parsed.positional.extend(args.by_ref().cloned());
+            break;
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            parsed.positional.push(arg.clone());
+            continue;
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        let spec = specs
+            .iter()
+            .find(|spec| spec.name == name)
+            .ok_or_else(|| format!("unknown argument --{}", name))?;
+
+        let value = match (value, spec.kind) {
+            (Some(value), _) => value,
+            (None, ArgType::Bool) => "true".to_string(),
+            (None, _) => args
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("--{} needs a value", name))?,
+        };
+        if parsed.named.contains_key(name) {
+            return Err(format!("--{} was given more than once", name));
+        }
+        parsed.named.insert(name.to_string(), spec.check(&value)?);
+    }
+
+    for spec in specs {
+        if parsed.named.contains_key(&spec.name) {
+            continue;
+        }
+        if spec.required {
+            return Err(format!("missing required argument {}", spec.synopsis()));
+        }
+        let default = match (&spec.default, spec.kind) {
+            (Some(default), _) => default.clone(),
+            (None, ArgType::Bool) => "false".to_string(),
+            (None, _) => String::new(),
+        };
+        parsed.named.insert(spec.name.clone(), default);
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, kind: ArgType) -> ArgSpec {
+        ArgSpec {
+            name: name.to_string(),
+            kind,
+            required: false,
+            choices: vec![],
+            default: None,
+            description: String::new(),
+        }
+    }
+
+    fn parse(specs: &[ArgSpec], args: &[&str]) -> Result<ParsedArgs, String> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        parse_args(specs, &args)
+    }
+
+    fn named(parsed: &ParsedArgs, name: &str) -> String {
+        parsed.named[name].clone()
+    }
+
+    #[test]
+    fn values_can_follow_the_flag_or_an_equals_sign() {
+        let specs = [spec("env", ArgType::String), spec("region", ArgType::String)];
+        let parsed = parse(&specs, &["--env", "prod", "--region=eu=west"]).unwrap();
+        assert_eq!(named(&parsed, "env"), "prod");
+        assert_eq!(named(&parsed, "region"), "eu=west");
+
+        assert_eq!(parse(&specs, &["--env"]).unwrap_err(), "--env needs a value");
+        assert_eq!(parse(&specs, &["--port", "1"]).unwrap_err(), "unknown argument --port");
+    }
+
+    #[test]
+    fn bool_flags_can_be_given_bare() {
+        let specs = [spec("force", ArgType::Bool), spec("dry-run", ArgType::Bool)];
+        let parsed = parse(&specs, &["--force", "file"]).unwrap();
+        assert_eq!(named(&parsed, "force"), "true");
+        assert_eq!(named(&parsed, "dry-run"), "false");
+        assert_eq!(parsed.positional, ["file"]);
+
+        let parsed = parse(&specs, &["--force=no"]).unwrap();
+        assert_eq!(named(&parsed, "force"), "false");
+    }
+
+    #[test]
+    fn everything_after_a_double_dash_is_positional() {
+        let specs = [spec("env", ArgType::String)];
+        let parsed = parse(&specs, &["a", "--env", "dev", "--", "--env", "b"]).unwrap();
+        assert_eq!(named(&parsed, "env"), "dev");
+        assert_eq!(parsed.positional, ["a", "--env", "b"]);
+    }
+
+    #[test]
+    fn commands_without_specs_get_everything_as_positional() {
+        let parsed = parse(&[], &["--env", "dev", "--"]).unwrap();
+        assert!(parsed.named.is_empty());
+        assert_eq!(parsed.positional, ["--env", "dev", "--"]);
+    }
+
+    #[test]
+    fn arguments_can_only_be_given_once() {
+        let specs = [spec("env", ArgType::String)];
+        assert_eq!(
+            parse(&specs, &["--env", "dev", "--env=prod"]).unwrap_err(),
+            "--env was given more than once"
+        );
+    }
+
+    #[test]
+    fn missing_arguments_are_required_or_defaulted() {
+        let mut env = spec("env", ArgType::String);
+        env.default = Some("dev".to_string());
+        let count = spec("count", ArgType::Int);
+        let parsed = parse(&[env.clone(), count], &[]).unwrap();
+        assert_eq!(named(&parsed, "env"), "dev");
+        assert_eq!(named(&parsed, "count"), "");
+
+        let mut region = spec("region", ArgType::String);
+        region.required = true;
+        assert_eq!(
+            parse(&[env, region], &[]).unwrap_err(),
+            "missing required argument --region <string>"
+        );
+    }
+
+    #[test]
+    fn values_must_be_one_of_the_choices() {
+        let mut env = spec("env", ArgType::String);
+        env.choices = vec!["dev".to_string(), "prod".to_string()];
+        assert_eq!(named(&parse(&[env.clone()], &["--env=prod"]).unwrap(), "env"), "prod");
+        assert_eq!(
+            parse(&[env], &["--env", "staging"]).unwrap_err(),
+            "--env: 'staging' isn't one of dev, prod"
+        );
+    }
+
+    #[test]
+    fn numbers_are_normalized() {
+        let specs = [spec("count", ArgType::Int), spec("ratio", ArgType::Float)];
+        let parsed = parse(&specs, &["--count", "+007", "--ratio", "1.50"]).unwrap();
+        assert_eq!(named(&parsed, "count"), "7");
+        assert_eq!(named(&parsed, "ratio"), "1.5");
+
+        assert_eq!(
+            parse(&specs, &["--count", "1.5"]).unwrap_err(),
+            "--count: '1.5' isn't a valid int"
+        );
+        assert_eq!(
+            parse(&specs, &["--ratio=fast"]).unwrap_err(),
+            "--ratio: 'fast' isn't a valid float"
+        );
+    }
+
+    #[test]
+    fn choices_are_compared_after_normalizing() {
+        let mut count = spec("count", ArgType::Int);
+        count.choices = vec!["1".to_string(), "2".to_string()];
+        assert_eq!(named(&parse(&[count], &["--count=02"]).unwrap(), "count"), "2");
+    }
+}
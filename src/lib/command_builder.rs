@@ -1,17 +1,27 @@
-use std::{collections::BTreeMap, fmt::{Display, Formatter}, process::Command};
+use std::{collections::BTreeMap, fmt::{Display, Formatter}, io::Write, process::Command};
 
 
-use crate::{color::Color, tomlp::SortRules};
+use crate::{
+    color::Color,
+    command_args::{parse_args, ArgSpec, ParsedArgs},
+    environment::Environment,
+    info::CommandInfo,
+    template::{arg_env_var, expand_runtime},
+    tomlp::SortRules,
+};
 
 use super::command_stage::Stage;
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommandBuilder {
     pub name: String,
     pub description: String,
     pub stages: BTreeMap<u8, Stage>,
     pub shell: String,
+    pub env: Environment,
+    /// Arguments the command declares, given as `--name value`.
+    pub args: Vec<ArgSpec>,
 }
 
 impl CommandBuilder {
@@ -21,6 +31,8 @@ impl CommandBuilder {
             description: desc,
             stages: BTreeMap::new(),
             shell,
+            env: Environment::default(),
+            args: Vec::new(),
         }
     }
 
@@ -38,15 +50,109 @@ impl CommandBuilder {
         &self.stages
     }
 
-    pub fn run(&self) {
+    /// Describes the command for `toaster list` and `toaster describe`.
+    pub fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            shell: self.shell.clone(),
+            stages: self
+                .stages
+                .values()
+                .map(|stage| stage.command.clone())
+                .collect(),
+            args: self.args.clone(),
+        }
+    }
+
+    /// Checks the arguments given to `toaster run` against the ones the command declares.
+    pub fn parse_args(&self, args: &[String]) -> Result<ParsedArgs, String> {
+        parse_args(&self.args, args)
+    }
+
+    /// Usage for `toaster help`, generated from the description and declared arguments.
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.clone();
+        if !self.description.is_empty() {
+            usage.push_str(&format!(" - {}", self.description));
+        }
+
+        usage.push_str(&format!("\n\nUsage: toaster run {}", self.name));
+        for arg in &self.args {
+            match arg.required {
+                true => usage.push_str(&format!(" {}", arg.synopsis())),
+                false => usage.push_str(&format!(" [{}]", arg.synopsis())),
+            }
+        }
+        usage.push_str(" [args...]");
+
+        if !self.args.is_empty() {
+            usage.push_str("\n\nArguments:");
+            let width = self.args.iter().map(|arg| arg.synopsis().len()).max().unwrap_or(0);
+            for arg in &self.args {
+                let mut notes = Vec::new();
+                if !arg.description.is_empty() {
+                    notes.push(arg.description.clone());
+                }
+                if arg.required {
+                    notes.push("required".to_string());
+                }
+                if let Some(default) = &arg.default {
+                    notes.push(format!("default: {}", default));
+                }
+                let line = format!("  {:width$}  {}", arg.synopsis(), notes.join("; "));
+                usage.push('\n');
+                usage.push_str(line.trim_end());
+            }
+        }
+
+        usage
+    }
+
+    /// Runs every stage in order, writing colored stdout to `out` and stderr to `err`.
+    ///
+    /// Declared arguments are handed to each stage in `TOASTER_ARG_<NAME>` variables, which
+    /// `${args.name}` refers to, and the rest are handed to each
+    /// stage as positional parameters (`$1`, `$2`, ...).
+    /// Returns the exit code of the last stage that failed, or `0` if every stage succeeded.
+    pub fn run<O: Write, E: Write>(
+        &self,
+        args: &ParsedArgs,
+        out: &mut O,
+        err: &mut E,
+    ) -> std::io::Result<i32> {
         let stages = self.get_map();
+        let mut exit_code = 0;
 
-        for (_, stage) in stages {
-            let output = Command::new(self.shell.as_str())
+        for (stage_id, stage) in stages {
+            let command = match expand_runtime(&stage.command, chrono::Local::now()) {
+                Ok(command) => command,
+                Err(e) => {
+                    writeln!(err, "\x1b[31mFailed to run stage {}: {}\x1b[0m", stage_id, e)?;
+                    exit_code = 1;
+                    continue;
+                }
+            };
+            let mut process = Command::new(self.shell.as_str());
+            process
                 .arg("-c")
-                .arg(stage.command.to_owned())
-                .output()
-                .expect("Failed to run command");
+                .arg(command)
+                .arg(self.name.as_str())
+                .args(&args.positional);
+            self.env.apply(&mut process);
+            process.envs(
+                args.named
+                    .iter()
+                    .map(|(name, value)| (arg_env_var(name), value)),
+            );
+            let output = match process.output() {
+                Ok(output) => output,
+                Err(e) => {
+                    writeln!(err, "\x1b[31mFailed to run stage {}: {}\x1b[0m", stage_id, e)?;
+                    exit_code = 127;
+                    continue;
+                }
+            };
 
             let output_str = String::from_utf8_lossy(&output.stdout);
             let error_str = String::from_utf8_lossy(&output.stderr);
@@ -72,24 +178,33 @@ impl CommandBuilder {
                             }
     
                             for line in grouped_lines {
-                                println!("{}{}{}", color_code, line, "\x1b[0m");
+                                writeln!(out, "{}{}{}", color_code, line, "\x1b[0m")?;
                             }
                         }
                     }
                 } else {
-                    if !output_str.is_empty() {
-                        println!("{}{}{}", color_code, output_str, "\x1b[0m");
-                    }
+                    writeln!(out, "{}{}{}", color_code, output_str, "\x1b[0m")?;
                 }
-            } else {
-                eprintln!("Failed to run command");
             }
+            out.flush()?;
 
             // Print error messages in red, if any
             if !error_str.is_empty() {
-                eprint!("\x1b[31m{}\x1b[0m", error_str); // Print errors in red
+                write!(err, "\x1b[31m{}\x1b[0m", error_str)?; // Print errors in red
+            }
+
+            if !output.status.success() {
+                exit_code = output.status.code().unwrap_or(1);
+                writeln!(
+                    err,
+                    "\x1b[31mStage {} exited with status {}\x1b[0m",
+                    stage_id, exit_code
+                )?;
             }
+            err.flush()?;
         }
+
+        Ok(exit_code)
     }
 }
 
@@ -103,4 +218,52 @@ impl Display for CommandBuilder {
         }
         write!(f, "{}", string)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_args::ArgType;
+
+    fn command_with_arg(stage: &str) -> CommandBuilder {
+        let mut command = CommandBuilder::new("test".to_string(), String::new(), "sh".to_string());
+        command.args.push(ArgSpec {
+            name: "msg".to_string(),
+            kind: ArgType::String,
+            required: true,
+            choices: vec![],
+            default: None,
+            description: String::new(),
+        });
+        command.add_stage(stage.to_string(), Color::White, None);
+        command
+    }
+
+    #[test]
+    fn args_reach_stages_as_one_literal_word() {
+        let command = command_with_arg("printf '<%s>' ${args.msg}");
+        let value = "a b; echo injected $(echo subshell) `echo tick` 'quoted' \"double\"";
+        let args = command
+            .parse_args(&["--msg".to_string(), value.to_string()])
+            .unwrap();
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let exit_code = command.run(&args, &mut out, &mut err).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(exit_code, 0, "stderr: {}", String::from_utf8_lossy(&err));
+        assert!(out.contains(&format!("<{}>", value)), "stdout: {}", out);
+        assert_eq!(out.matches('<').count(), 1, "stdout: {}", out);
+    }
+
+    #[test]
+    fn args_can_be_used_inside_double_quotes() {
+        let command = command_with_arg("printf '<%s>' \"hello ${args.msg}!\"");
+        let args = command
+            .parse_args(&["--msg=big world".to_string()])
+            .unwrap();
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        command.run(&args, &mut out, &mut err).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("<hello big world!>"));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{color::Color, tomlp::SortRules};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stage {
     /// The command to run
     pub command: String,
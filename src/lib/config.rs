@@ -0,0 +1,822 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{
+    de::{self, value::MapAccessDeserializer, DeserializeOwned, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use toml::{Spanned, Table, Value};
+
+use crate::{
+    command_args::ArgType,
+    duration::duration_from_toml,
+    logs::{LogFormat, LogRoute, LogSettings, DEFAULT_FLUSH_INTERVAL, DEFAULT_MAX_QUEUE_SIZE},
+    size::size_from_toml,
+    system_builder::CatchUp,
+};
+
+/// A single thing wrong with the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// The file the problem is in, when the config was read from a file.
+    pub file: Option<PathBuf>,
+    /// Where the problem is, e.g. `system.backup.schedules`.
+    pub path: String,
+    /// The 1-based line the problem is on, when it could be found.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file.display(), line)?,
+            (Some(file), None) => write!(f, "{}: ", file.display())?,
+            (None, Some(line)) => write!(f, "line {}: ", line)?,
+            (None, None) => {}
+        }
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Everything wrong with a config, so it can all be fixed in one go.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl ConfigError {
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn push(&mut self, path: impl Into<String>, line: Option<usize>, message: impl Into<String>) {
+        self.problems.push(ConfigProblem {
+            file: None,
+            path: path.into(),
+            line,
+            message: message.into(),
+        });
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Found {} problem(s) in the config:", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A duration written as seconds or a string like `"30s"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Value")]
+pub struct DurationValue(pub Duration);
+
+impl TryFrom<Value> for DurationValue {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        duration_from_toml(&value).map(DurationValue)
+    }
+}
+
+/// A size written as bytes or a string like `"10MB"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Value")]
+pub struct SizeValue(pub u64);
+
+impl TryFrom<Value> for SizeValue {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        size_from_toml(&value).map(SizeValue)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SettingsConfig {
+    pub threads: usize,
+    pub default_row_length: usize,
+    pub shutdown_timeout: u64,
+    pub socket: Option<PathBuf>,
+    /// Defaults for every system and command.
+    pub env: BTreeMap<String, EnvValue>,
+    pub env_file: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub clear_env: Option<bool>,
+    pub log_format: LogFormat,
+    pub logs: LogsConfig,
+}
+
+impl SettingsConfig {
+    pub fn env(&self) -> EnvConfig {
+        EnvConfig {
+            env: self.env.clone(),
+            env_file: self.env_file.clone(),
+            cwd: self.cwd.clone(),
+            clear_env: self.clear_env,
+        }
+    }
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            default_row_length: 4,
+            shutdown_timeout: 10,
+            socket: None,
+            env: BTreeMap::new(),
+            env_file: None,
+            cwd: None,
+            clear_env: None,
+            log_format: LogFormat::default(),
+            logs: LogsConfig::default(),
+        }
+    }
+}
+
+/// `[settings.logs]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LogsConfig {
+    pub route: LogRoute,
+    pub max_file_size: Option<SizeValue>,
+    pub compress: bool,
+    pub max_age: Option<DurationValue>,
+    pub max_total_size: Option<SizeValue>,
+    pub max_files: Option<usize>,
+    pub flush_interval: Option<DurationValue>,
+    pub max_queue_size: Option<usize>,
+}
+
+impl LogsConfig {
+    pub fn settings(&self, format: LogFormat) -> LogSettings {
+        LogSettings {
+            route: self.route,
+            format,
+            max_file_size: self.max_file_size.map(|size| size.0),
+            compress: self.compress,
+            max_age: self.max_age.map(|age| age.0),
+            max_total_size: self.max_total_size.map(|size| size.0),
+            max_files: self.max_files,
+            flush_interval: self
+                .flush_interval
+                .map_or(DEFAULT_FLUSH_INTERVAL, |interval| interval.0),
+            max_queue_size: self.max_queue_size.unwrap_or(DEFAULT_MAX_QUEUE_SIZE),
+        }
+    }
+}
+
+/// An environment variable's value. Numbers and booleans are taken as they're written.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Value")]
+pub struct EnvValue(pub String);
+
+impl TryFrom<Value> for EnvValue {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(s) => Ok(EnvValue(s)),
+            Value::Integer(i) => Ok(EnvValue(i.to_string())),
+            Value::Float(f) => Ok(EnvValue(f.to_string())),
+            Value::Boolean(b) => Ok(EnvValue(b.to_string())),
+            other => Err(format!(
+                "expected a string, number or boolean, found {}",
+                other.type_str()
+            )),
+        }
+    }
+}
+
+/// The environment options shared by settings, systems and commands.
+#[derive(Debug, Clone, Default)]
+pub struct EnvConfig {
+    pub env: BTreeMap<String, EnvValue>,
+    pub env_file: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub clear_env: Option<bool>,
+}
+
+/// The retry options shared by systems and their stages.
+#[derive(Debug, Clone, Default)]
+pub struct RetryConfig {
+    pub retries: Option<u32>,
+    pub retry_delay: Option<DurationValue>,
+    pub retry_backoff: Option<f64>,
+    pub max_retry_delay: Option<DurationValue>,
+}
+
+/// A system stage: either just a command, or a table with a command and its options.
+#[derive(Debug, Clone)]
+pub struct StageConfig {
+    pub command: String,
+    pub timeout: Option<DurationValue>,
+    pub retry: RetryConfig,
+}
+
+#[derive(Deserialize)]
+struct StageTable {
+    command: String,
+    timeout: Option<DurationValue>,
+    retries: Option<u32>,
+    retry_delay: Option<DurationValue>,
+    retry_backoff: Option<f64>,
+    max_retry_delay: Option<DurationValue>,
+}
+
+impl<'de> Deserialize<'de> for StageConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StageVisitor;
+
+        impl<'de> Visitor<'de> for StageVisitor {
+            type Value = StageConfig;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("a command string or a table with a `command`")
+            }
+
+            fn visit_str<E: de::Error>(self, command: &str) -> Result<StageConfig, E> {
+                Ok(StageConfig {
+                    command: command.to_string(),
+                    timeout: None,
+                    retry: RetryConfig::default(),
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<StageConfig, A::Error> {
+                let stage = StageTable::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(StageConfig {
+                    command: stage.command,
+                    timeout: stage.timeout,
+                    retry: RetryConfig {
+                        retries: stage.retries,
+                        retry_delay: stage.retry_delay,
+                        retry_backoff: stage.retry_backoff,
+                        max_retry_delay: stage.max_retry_delay,
+                    },
+                })
+            }
+        }
+
+        deserializer.deserialize_any(StageVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMode {
+    #[default]
+    Independent,
+    Pipeline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFailureConfig {
+    #[default]
+    Abort,
+    Continue,
+    Cleanup,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SystemConfig {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_shell")]
+    pub shell: String,
+    pub stages: Vec<StageConfig>,
+    #[serde(default)]
+    pub schedules: Vec<String>,
+    #[serde(default)]
+    pub mode: SystemMode,
+    /// The single schedule of a pipeline.
+    pub schedule: Option<String>,
+    #[serde(default)]
+    pub on_failure: OnFailureConfig,
+    pub cleanup: Option<StageConfig>,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+    pub timeout: Option<DurationValue>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<DurationValue>,
+    pub retry_backoff: Option<f64>,
+    pub max_retry_delay: Option<DurationValue>,
+    #[serde(default)]
+    pub env: BTreeMap<String, EnvValue>,
+    pub env_file: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub clear_env: Option<bool>,
+}
+
+impl SystemConfig {
+    pub fn env(&self) -> EnvConfig {
+        EnvConfig {
+            env: self.env.clone(),
+            env_file: self.env_file.clone(),
+            cwd: self.cwd.clone(),
+            clear_env: self.clear_env,
+        }
+    }
+
+    pub fn retry(&self) -> RetryConfig {
+        RetryConfig {
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            retry_backoff: self.retry_backoff,
+            max_retry_delay: self.max_retry_delay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandConfig {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_shell")]
+    pub shell: String,
+    pub stages: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<ArgConfig>,
+    #[serde(default)]
+    pub env: BTreeMap<String, EnvValue>,
+    pub env_file: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    pub clear_env: Option<bool>,
+}
+
+impl CommandConfig {
+    pub fn env(&self) -> EnvConfig {
+        EnvConfig {
+            env: self.env.clone(),
+            env_file: self.env_file.clone(),
+            cwd: self.cwd.clone(),
+            clear_env: self.clear_env,
+        }
+    }
+}
+
+/// An argument a command declares, like `{ name = "count", type = "int", default = 3 }`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArgConfig {
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub kind: ArgType,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub choices: Vec<EnvValue>,
+    pub default: Option<EnvValue>,
+    #[serde(default)]
+    pub description: String,
+}
+
+fn default_shell() -> String {
+    "sh".to_string()
+}
+
+/// The config as read from TOML, with every entry's location so problems can point at it.
+#[derive(Debug, Default, Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    include: Option<Spanned<Vec<String>>>,
+    /// Not spanned, since toml can't span a table only defined through its sub-tables,
+    /// like a lone `[settings.logs]`. See `table_span`.
+    settings: Option<Table>,
+    vars: Option<Spanned<Table>>,
+    #[serde(default)]
+    system: BTreeMap<String, Spanned<Table>>,
+    #[serde(default)]
+    command: BTreeMap<String, Spanned<Table>>,
+}
+
+/// A config file's contents, kept so problems can point at a line in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    /// The file the config was read from, if it came from one.
+    pub file: Option<PathBuf>,
+    pub content: String,
+}
+
+impl Source {
+    /// A problem at `line` of this source.
+    pub fn problem(
+        &self,
+        path: impl Into<String>,
+        line: Option<usize>,
+        message: impl Into<String>,
+    ) -> ConfigProblem {
+        ConfigProblem {
+            file: self.file.clone(),
+            path: path.into(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// A config entry that deserialized cleanly, with where it was written.
+#[derive(Debug, Clone)]
+pub struct Entry<T> {
+    pub value: T,
+    pub span: Range<usize>,
+    pub source: Arc<Source>,
+}
+
+impl<T> Entry<T> {
+    /// The line `key` is on, or the entry's header line if it isn't written out.
+    pub fn line_of(&self, key: &str) -> usize {
+        let content = &self.source.content;
+        line_of_key(content, self.span.clone(), key)
+            .unwrap_or_else(|| line_at(content, self.span.start))
+    }
+
+    /// A problem with `key` in this entry.
+    pub fn problem(
+        &self,
+        path: impl Into<String>,
+        key: &str,
+        message: impl Into<String>,
+    ) -> ConfigProblem {
+        self.source.problem(path, Some(self.line_of(key)), message)
+    }
+}
+
+/// The typed config, before it's turned into systems and commands.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// `[settings]`, if the root config has it.
+    pub settings: Option<Entry<SettingsConfig>>,
+    /// `[vars]`, which stage commands can use as `${name}`.
+    pub vars: Option<Entry<BTreeMap<String, EnvValue>>>,
+    pub systems: BTreeMap<String, Entry<SystemConfig>>,
+    pub commands: BTreeMap<String, Entry<CommandConfig>>,
+    /// Keys the config doesn't know about. They're ignored, but usually mean a typo.
+    pub unknown_keys: Vec<ConfigProblem>,
+    /// Every file the config was read from, starting with the root.
+    pub files: Vec<PathBuf>,
+    /// What was read from each of `files`, in the same order.
+    pub sources: Vec<Arc<Source>>,
+}
+
+impl Config {
+    /// `[settings]`, or the defaults if the root config doesn't have it.
+    pub fn settings(&self) -> SettingsConfig {
+        self.settings
+            .as_ref()
+            .map(|settings| settings.value.clone())
+            .unwrap_or_default()
+    }
+
+    /// Parses a config, collecting a problem for every entry that doesn't fit the schema.
+    /// Entries with problems are left out of the returned config. Includes aren't followed.
+    pub fn parse(content: &str) -> (Config, ConfigError) {
+        let source = Arc::new(Source {
+            file: None,
+            content: content.to_string(),
+        });
+        let mut errors = ConfigError::default();
+        let (config, _) = Config::parse_source(source, true, &mut errors);
+        (config, errors)
+    }
+
+    /// Reads the config at `path` and merges in the systems and commands of every file its
+    /// `include` patterns match. Patterns are relative to the directory `path` is in.
+    pub fn load(path: &Path) -> (Config, ConfigError) {
+        let mut errors = ConfigError::default();
+        let source = match read_source(path) {
+            Ok(source) => source,
+            Err(problem) => {
+                errors.problems.push(problem);
+                return (Config::default(), errors);
+            }
+        };
+
+        let (mut config, include) = Config::parse_source(source.clone(), true, &mut errors);
+        config.files.push(path.to_path_buf());
+        config.sources.push(source.clone());
+
+        let Some(include) = include else {
+            return (config, errors);
+        };
+        let line = Some(line_at(&source.content, include.span().start));
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        for pattern in include.into_inner() {
+            let files = match include_files(base, &pattern) {
+                Ok(files) => files,
+                Err(message) => {
+                    errors.problems.push(source.problem("include", line, message));
+                    continue;
+                }
+            };
+
+            for file in files {
+                if config.files.contains(&file) {
+                    continue;
+                }
+                let file_source = match read_source(&file) {
+                    Ok(file_source) => file_source,
+                    Err(problem) => {
+                        errors.problems.push(problem);
+                        continue;
+                    }
+                };
+
+                let (included, _) = Config::parse_source(file_source.clone(), false, &mut errors);
+                config.files.push(file);
+                config.sources.push(file_source);
+                config.unknown_keys.extend(included.unknown_keys);
+                merge("system", &mut config.systems, included.systems, &mut errors);
+                merge("command", &mut config.commands, included.commands, &mut errors);
+            }
+        }
+
+        (config, errors)
+    }
+
+    /// Parses one file. Only the root may set `[settings]` and `include`.
+    fn parse_source(
+        source: Arc<Source>,
+        root: bool,
+        errors: &mut ConfigError,
+    ) -> (Config, Option<Spanned<Vec<String>>>) {
+        let content = source.content.as_str();
+        let mut unknown = Vec::new();
+        let deserializer = toml::Deserializer::new(content);
+        let raw = match serde_ignored::deserialize(deserializer, |path| {
+            unknown.push(path.to_string())
+        }) {
+            Ok(raw) => raw,
+            Err(e) => {
+                let line = e.span().map(|span| line_at(content, span.start));
+                let message = e.message().trim().replace('\n', "; ");
+                errors.problems.push(source.problem("<root>", line, message));
+                return (Config::default(), None);
+            }
+        };
+
+        let mut config = Config::default();
+        for key in unknown {
+            let line = line_of_key(content, 0..content.len(), &key)
+                .or_else(|| content.find(&format!("[{}", key)).map(|at| line_at(content, at)));
+            config
+                .unknown_keys
+                .push(source.problem(key, line, "unknown key"));
+        }
+        let raw: RawConfig = raw;
+
+        if !root {
+            if let Some(include) = &raw.include {
+                let line = Some(line_at(content, include.span().start));
+                let message = "include can only be used in the root config";
+                errors.problems.push(source.problem("include", line, message));
+            }
+            let settings = raw.settings.as_ref().map(|_| table_span(content, "settings"));
+            let vars = raw.vars.as_ref().map(|vars| vars.span());
+            for (key, span) in [("settings", settings), ("vars", vars)] {
+                if let Some(span) = span {
+                    let line = Some(line_at(content, span.start));
+                    let message = format!("{} can only be set in the root config", key);
+                    errors.problems.push(source.problem(key, line, message));
+                }
+            }
+        } else {
+            if let Some(settings) = raw.settings {
+                let entry = Entry {
+                    span: table_span(content, "settings"),
+                    value: settings,
+                    source: source.clone(),
+                };
+                config.settings =
+                    deserialize_entry("settings", entry, errors, &mut config.unknown_keys);
+            }
+            if let Some(vars) = raw.vars {
+                let entry = Entry {
+                    span: vars.span(),
+                    value: vars.into_inner(),
+                    source: source.clone(),
+                };
+                config.vars = deserialize_entry("vars", entry, errors, &mut config.unknown_keys);
+            }
+        }
+
+        for (name, system) in raw.system {
+            let path = format!("system.{}", name);
+            let entry = Entry {
+                span: system.span(),
+                value: system.into_inner(),
+                source: source.clone(),
+            };
+            if let Some(entry) = deserialize_entry(&path, entry, errors, &mut config.unknown_keys) {
+                config.systems.insert(name, entry);
+            }
+        }
+
+        for (name, command) in raw.command {
+            let path = format!("command.{}", name);
+            let entry = Entry {
+                span: command.span(),
+                value: command.into_inner(),
+                source: source.clone(),
+            };
+            if let Some(entry) = deserialize_entry(&path, entry, errors, &mut config.unknown_keys) {
+                config.commands.insert(name, entry);
+            }
+        }
+
+        (config, if root { raw.include } else { None })
+    }
+}
+
+fn read_source(path: &Path) -> Result<Arc<Source>, ConfigProblem> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Arc::new(Source {
+            file: Some(path.to_path_buf()),
+            content,
+        })),
+        Err(e) => Err(ConfigProblem {
+            file: Some(path.to_path_buf()),
+            path: "<root>".to_string(),
+            line: None,
+            message: format!("couldn't read the file: {}", e),
+        }),
+    }
+}
+
+/// The files an include pattern matches, in sorted order.
+fn include_files(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let full = base.join(pattern);
+    let full = full.to_string_lossy();
+    let paths = glob::glob(&full).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+
+    let mut files = Vec::new();
+    for path in paths {
+        match path {
+            Ok(path) if path.is_file() => files.push(path),
+            Ok(_) => {}
+            Err(e) => return Err(format!("couldn't read {}: {}", e.path().display(), e.error())),
+        }
+    }
+    // A pattern without wildcards names one file, which should exist.
+    if files.is_empty() && !pattern.contains(['*', '?', '[']) {
+        return Err(format!("included file {} doesn't exist", full));
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Adds included entries, reporting any that are already defined along with where.
+fn merge<T>(
+    kind: &str,
+    entries: &mut BTreeMap<String, Entry<T>>,
+    included: BTreeMap<String, Entry<T>>,
+    errors: &mut ConfigError,
+) {
+    for (name, entry) in included {
+        if let Some(existing) = entries.get(&name) {
+            let first = match &existing.source.file {
+                Some(file) => format!("{}:{}", file.display(), existing.line_of("")),
+                None => format!("line {}", existing.line_of("")),
+            };
+            let message = format!("{} '{}' is already defined at {}", kind, name, first);
+            errors
+                .problems
+                .push(entry.problem(format!("{}.{}", kind, name), "", message));
+            continue;
+        }
+        entries.insert(name, entry);
+    }
+}
+
+fn deserialize_entry<T: DeserializeOwned>(
+    path: &str,
+    entry: Entry<Table>,
+    errors: &mut ConfigError,
+    unknown_keys: &mut Vec<ConfigProblem>,
+) -> Option<Entry<T>> {
+    let result = serde_ignored::deserialize(Value::Table(entry.value.clone()), |key| {
+        let key = key.to_string();
+        let line = line_of_key(&entry.source.content, entry.span.clone(), &key);
+        let problem = entry
+            .source
+            .problem(format!("{}.{}", path, key), line, "unknown key");
+        unknown_keys.push(problem);
+    });
+    match result {
+        Ok(value) => Some(Entry {
+            value,
+            span: entry.span,
+            source: entry.source,
+        }),
+        Err(e) => {
+            let (message, key) = clean_message(&e.to_string());
+            let problem = match key {
+                Some(key) => entry.problem(format!("{}.{}", path, key), &key, message),
+                None => entry.problem(path, "", message),
+            };
+            errors.problems.push(problem);
+            None
+        }
+    }
+}
+
+/// Splits a TOML deserialization message into the message itself and the key it's about.
+/// The messages look like ``invalid type: integer `3`, expected a string\nin `description`\n``.
+fn clean_message(message: &str) -> (String, Option<String>) {
+    let message = message.trim();
+    match message.rsplit_once("\nin `") {
+        Some((message, key)) => (
+            message.trim().to_string(),
+            Some(key.trim_end_matches('`').to_string()),
+        ),
+        None => (message.to_string(), None),
+    }
+}
+
+/// Where a root table is written: from the first line that defines it, whether `[name]`,
+/// `[name.sub]` or `name = ...`, up to the next table header that isn't part of it.
+fn table_span(content: &str, name: &str) -> Range<usize> {
+    let belongs = |line: &str| {
+        let line = line.trim_start();
+        match line.strip_prefix('[') {
+            Some(header) => {
+                let header = header.trim_start();
+                header.starts_with(&format!("{}]", name)) || header.starts_with(&format!("{}.", name))
+            }
+            None => line
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.trim_start().starts_with(['=', '.'])),
+        }
+    };
+
+    let mut start = None;
+    let mut offset = 0;
+    let mut in_root = true;
+    for line in content.split_inclusive('\n') {
+        let is_header = line.trim_start().starts_with('[');
+        match start {
+            None if (is_header || in_root) && belongs(line) => start = Some(offset),
+            Some(start) if is_header && !belongs(line) => return start..offset,
+            _ => {}
+        }
+        in_root &= !is_header;
+        offset += line.len();
+    }
+    start.unwrap_or(0)..content.len()
+}
+
+/// The 1-based line number of a byte offset.
+pub fn line_at(content: &str, offset: usize) -> usize {
+    content[..offset.min(content.len())].matches('\n').count() + 1
+}
+
+/// Finds the line a dotted key like `stages.timeout` is written on within an entry.
+pub fn line_of_key(content: &str, span: Range<usize>, key: &str) -> Option<usize> {
+    let entry = content.get(span.clone())?;
+    let mut offset = 0;
+
+    for part in key.split('.') {
+        let part = part.split('[').next().unwrap_or(part);
+        // Array indices aren't written in the file.
+        if part.is_empty() || part.parse::<usize>().is_ok() {
+            continue;
+        }
+        offset += find_key(&entry[offset..], part)?;
+    }
+
+    Some(line_at(content, span.start + offset))
+}
+
+/// Finds `key =` in `text`, returning the offset of the key.
+fn find_key(text: &str, key: &str) -> Option<usize> {
+    let mut start = 0;
+    while let Some(found) = text[start..].find(key) {
+        let at = start + found;
+        let before = text[..at].chars().last();
+        let after = text[at + key.len()..].trim_start();
+        let at_boundary = before.is_none_or(|c| !c.is_alphanumeric() && c != '_');
+        // Sub-tables are usually written as a header, like `[settings.logs]`.
+        let in_header = matches!(before, Some('.' | '[')) && after.starts_with(']');
+        if at_boundary && (after.starts_with('=') || in_header) {
+            return Some(at);
+        }
+        start = at + key.len();
+    }
+    None
+}
@@ -0,0 +1,296 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// How far ahead `next_after` searches before giving up on an expression that never matches,
+/// such as `0 0 31 2 *`.
+const SEARCH_LIMIT_DAYS: i64 = 366 * 5;
+
+/// A standard five-field cron expression: `minute hour day-of-month month day-of-week`.
+///
+/// Each field accepts `*`, single values, ranges (`1-5`), steps (`*/15`, `10-40/10`) and
+/// comma separated lists of those. Months and days of the week also accept their three
+/// letter names (`jan`, `mon-fri`). The `@yearly`, `@monthly`, `@weekly`, `@daily` and
+/// `@hourly` shorthands are supported too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    /// The expression as it was written in the config.
+    pub source: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether day-of-month started with `*`, like `*` or `*/2`; cron ORs the two day fields
+    /// unless one of them does.
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let source = s.trim();
+        let expanded = match source.to_lowercase().as_str() {
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@hourly" => "0 * * * *".to_string(),
+            other if other.starts_with('@') => {
+                return Err(format!("Unknown cron shorthand '{}'", source))
+            }
+            other => other.to_string(),
+        };
+
+        let fields = expanded.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Cron expression '{}' needs 5 fields (minute hour day month weekday), found {}",
+                source,
+                fields.len()
+            ));
+        }
+
+        let minutes = parse_field(fields[0], 0, 59, &[])?;
+        let hours = parse_field(fields[1], 0, 23, &[])?;
+        let days_of_month = parse_field(fields[2], 1, 31, &[])?;
+        let months = parse_field(fields[3], 1, 12, &MONTH_NAMES)?;
+        // 7 is accepted as an alias for Sunday.
+        let mut days_of_week = parse_field(fields[4], 0, 7, &DAY_NAMES)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week & !(1 << 7)) | 1;
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            minutes,
+            hours,
+            days_of_month,
+            months,
+            days_of_week,
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl CronExpr {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let dom = self.days_of_month & (1 << date.day()) != 0;
+        let dow = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // A starred field covers every day it can, so ANDing leaves just the other's restriction.
+        if self.any_day_of_month || self.any_day_of_week {
+            dom && dow
+        } else {
+            dom || dow
+        }
+    }
+
+    /// Finds the first local time strictly after `after` that this expression matches.
+    /// Returns `None` if nothing matches within the next few years.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(SEARCH_LIMIT_DAYS);
+        let mut time = start;
+
+        while time < limit {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = if time.month() == 12 {
+                    (time.year() + 1, 1)
+                } else {
+                    (time.year(), time.month() + 1)
+                };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+
+            if !self.matches_day(time.date()) {
+                time = (time.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+
+            if self.hours & (1 << time.hour()) == 0 {
+                time = truncate_to_hour(time)? + Duration::hours(1);
+                continue;
+            }
+
+            if self.minutes & (1 << time.minute()) == 0 {
+                time += Duration::minutes(1);
+                continue;
+            }
+
+            // Times skipped by a DST change don't exist locally; ambiguous ones take the first.
+            match Local.from_local_datetime(&time) {
+                LocalResult::Single(found) | LocalResult::Ambiguous(found, _) if found > after => {
+                    return Some(found)
+                }
+                _ => time += Duration::minutes(1),
+            }
+        }
+
+        None
+    }
+}
+
+fn truncate_to_hour(time: NaiveDateTime) -> Option<NaiveDateTime> {
+    time.with_minute(0)?.with_second(0)
+}
+
+/// Parses one cron field into a bitset where bit `n` means value `n` matches.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut bits = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid step '{}' in cron field '{}'", step, field))?;
+                if step == 0 {
+                    return Err(format!("Step can't be 0 in cron field '{}'", field));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, names, field)?,
+                parse_value(end, min, names, field)?,
+            )
+        } else {
+            let value = parse_value(range, min, names, field)?;
+            // `5/15` means "starting at 5, every 15".
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "Range '{}' in cron field '{}' must be within {}-{}",
+                range, field, min, max
+            ));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+fn parse_value(value: &str, min: u32, names: &[&str], field: &str) -> Result<u32, String> {
+    if let Some(index) = names.iter().position(|name| value.eq_ignore_ascii_case(name)) {
+        // Month names start at 1, day names at 0.
+        return Ok(index as u32 + min.min(1));
+    }
+
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid value '{}' in cron field '{}'", value, field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(s: &str) -> CronExpr {
+        s.parse().unwrap()
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .single()
+            .unwrap()
+    }
+
+    fn next(s: &str, after: DateTime<Local>) -> DateTime<Local> {
+        cron(s).next_after(after).unwrap()
+    }
+
+    #[test]
+    fn parses_fields_into_bitsets() {
+        let expr = cron("0,30 8-10 */10 jan-mar mon-fri");
+        assert_eq!(expr.minutes, 1 | 1 << 30);
+        assert_eq!(expr.hours, 1 << 8 | 1 << 9 | 1 << 10);
+        assert_eq!(expr.days_of_month, 1 << 1 | 1 << 11 | 1 << 21 | 1 << 31);
+        assert_eq!(expr.months, 1 << 1 | 1 << 2 | 1 << 3);
+        assert_eq!(expr.days_of_week, 0b0111110);
+
+        assert_eq!(cron("5/20 * * * *").minutes, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(cron("0 0 * * 7").days_of_week, 1);
+        assert_eq!(cron("@daily"), CronExpr { source: "@daily".to_string(), ..cron("0 0 * * *") });
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        for invalid in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "* * * foo *",
+            "@sometimes",
+        ] {
+            assert!(invalid.parse::<CronExpr>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn next_run_crosses_month_and_year_ends() {
+        assert_eq!(next("0 12 1 * *", local(2024, 1, 31, 13, 0)), local(2024, 2, 1, 12, 0));
+        assert_eq!(next("0 12 * * *", local(2024, 12, 31, 12, 0)), local(2025, 1, 1, 12, 0));
+        // February 2024 has a 29th, 2023 doesn't.
+        assert_eq!(next("0 12 29 2 *", local(2023, 1, 1, 0, 0)), local(2024, 2, 29, 12, 0));
+        assert_eq!(next("0 12 31 * *", local(2024, 4, 1, 0, 0)), local(2024, 5, 31, 12, 0));
+        assert!(cron("0 0 31 2 *").next_after(local(2024, 1, 1, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn next_run_is_strictly_after() {
+        assert_eq!(next("*/15 * * * *", local(2024, 5, 1, 9, 0)), local(2024, 5, 1, 9, 15));
+        assert_eq!(next("*/15 * * * *", local(2024, 5, 1, 9, 59)), local(2024, 5, 1, 10, 0));
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // 2024-05-01 is a Wednesday. The 15th or any Monday, whichever comes first.
+        assert_eq!(next("0 12 15 * mon", local(2024, 5, 1, 0, 0)), local(2024, 5, 6, 12, 0));
+        assert_eq!(next("0 12 2 * mon", local(2024, 5, 1, 0, 0)), local(2024, 5, 2, 12, 0));
+    }
+
+    #[test]
+    fn an_unrestricted_day_field_is_ignored() {
+        assert_eq!(next("0 12 * * mon", local(2024, 5, 1, 0, 0)), local(2024, 5, 6, 12, 0));
+        assert_eq!(next("0 12 15 * *", local(2024, 5, 1, 0, 0)), local(2024, 5, 15, 12, 0));
+    }
+
+    #[test]
+    fn a_starred_step_counts_as_unrestricted() {
+        // Like Vixie cron, `*/2` in day-of-month doesn't turn on the OR with day-of-week:
+        // this is odd days that are also Mondays, the first being 2024-05-13.
+        assert_eq!(next("0 12 */2 * mon", local(2024, 5, 1, 0, 0)), local(2024, 5, 13, 12, 0));
+    }
+}
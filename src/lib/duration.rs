@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use toml::Value;
+
+/// Parses a human readable duration such as `"30s"`, `"5m"`, `"1h30m"` or `"250ms"`.
+/// A bare number is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let invalid = || format!("Invalid duration '{}'; expected something like 30s, 5m or 1h30m", s);
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        if digits == 0 {
+            return Err(invalid());
+        }
+        let amount = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(amount),
+            "s" => Duration::from_secs(amount),
+            "m" => Duration::from_secs(amount * 60),
+            "h" => Duration::from_secs(amount * 60 * 60),
+            "d" => Duration::from_secs(amount * 60 * 60 * 24),
+            _ => return Err(invalid()),
+        };
+        total += unit;
+        rest = &rest[unit_len..];
+    }
+
+    Ok(total)
+}
+
+/// Reads a duration from TOML, either an integer number of seconds or a string for `parse_duration`.
+pub fn duration_from_toml(value: &Value) -> Result<Duration, String> {
+    match value {
+        Value::Integer(secs) if *secs >= 0 => Ok(Duration::from_secs(*secs as u64)),
+        Value::String(s) => parse_duration(s),
+        other => Err(format!(
+            "Invalid duration {}; expected seconds or a string like \"30s\"",
+            other
+        )),
+    }
+}
+
+/// Formats a duration the way `parse_duration` reads it, e.g. `1h30m` or `250ms`.
+/// Anything under a second is shown in milliseconds, otherwise milliseconds are dropped.
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    if secs == 0 {
+        return format!("{}ms", duration.as_millis());
+    }
+
+    let mut formatted = String::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if secs >= size {
+            formatted.push_str(&format!("{}{}", secs / size, unit));
+            secs %= size;
+        }
+    }
+    formatted
+}
@@ -0,0 +1,55 @@
+use std::{collections::BTreeMap, path::PathBuf, process::Command};
+
+/// The environment and working directory a system's or command's stages run with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    /// Variables set on top of the daemon's environment, or on their own if `clear_env` is set.
+    pub vars: BTreeMap<String, String>,
+    /// Where stages run; the daemon's working directory when `None`.
+    pub cwd: Option<PathBuf>,
+    /// Start stages with an empty environment instead of the daemon's.
+    pub clear_env: bool,
+}
+
+impl Environment {
+    pub fn apply(&self, command: &mut Command) {
+        if self.clear_env {
+            command.env_clear();
+        }
+        command.envs(&self.vars);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+    }
+}
+
+/// Parses an env file: `KEY=value` lines, with blank lines and `#` comments ignored.
+/// Lines may start with `export`, and values may be wrapped in single or double quotes.
+pub fn parse_env_file(content: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut vars = BTreeMap::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=value", index + 1))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: invalid variable name '{}'", index + 1, key));
+        }
+
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+            .unwrap_or(value);
+        vars.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(vars)
+}
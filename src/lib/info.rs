@@ -0,0 +1,286 @@
+use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{command_args::ArgSpec, duration::format_duration};
+
+/// Everything the daemon has loaded, as sent back for `toaster list` and `toaster describe`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Listing {
+    pub systems: Vec<SystemInfo>,
+    pub commands: Vec<CommandInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemInfo {
+    pub name: String,
+    pub description: String,
+    pub shell: String,
+    pub running: bool,
+    /// `independent` or `pipeline`.
+    pub mode: String,
+    pub stages: Vec<StageInfo>,
+    /// The pipeline's schedule, when the stages run as one pipeline.
+    pub pipeline: Option<ScheduleInfo>,
+}
+
+impl SystemInfo {
+    /// Every schedule the system has: the pipeline's, or each stage's.
+    pub fn schedules(&self) -> Vec<&ScheduleInfo> {
+        match &self.pipeline {
+            Some(pipeline) => vec![pipeline],
+            None => self.stages.iter().filter_map(|s| s.schedule.as_ref()).collect(),
+        }
+    }
+
+    /// When the system next runs anything, in unix seconds.
+    pub fn next_run(&self) -> Option<u64> {
+        self.next_due().map(|due| due.at)
+    }
+
+    /// Whichever stage, or the pipeline, is due first.
+    pub fn next_due(&self) -> Option<NextDue> {
+        let pipeline = self.pipeline.as_ref().and_then(|pipeline| {
+            Some(NextDue {
+                what: "pipeline".to_string(),
+                at: pipeline.next_run?,
+            })
+        });
+        let stage = self
+            .stages
+            .iter()
+            .filter_map(|stage| {
+                Some(NextDue {
+                    what: format!("stage {}", stage.id),
+                    at: stage.schedule.as_ref()?.next_run?,
+                })
+            })
+            .min_by_key(|due| due.at);
+        pipeline.or(stage)
+    }
+
+    /// The most recent run of any of its schedules.
+    pub fn last_run(&self) -> Option<&LastRun> {
+        self.schedules()
+            .into_iter()
+            .filter_map(|s| s.last_run.as_ref())
+            .max_by_key(|run| run.at)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageInfo {
+    pub id: u8,
+    pub command: String,
+    /// `None` for stages of a pipeline, which run on the pipeline's schedule.
+    pub schedule: Option<ScheduleInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleInfo {
+    /// The schedule as written in the config.
+    pub schedule: String,
+    /// Unix seconds; `None` when the system isn't running or the schedule never matches.
+    pub next_run: Option<u64>,
+    pub last_run: Option<LastRun>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastRun {
+    /// Unix seconds.
+    pub at: u64,
+    /// How it ended, e.g. `ok` or `FAILED (exit 3)`.
+    pub outcome: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub description: String,
+    pub shell: String,
+    pub stages: Vec<String>,
+    pub args: Vec<ArgSpec>,
+}
+
+/// What the daemon is doing, as sent back for `toaster status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    /// The daemon's clock when the status was taken, in unix seconds.
+    pub now: u64,
+    /// When the daemon started, in unix seconds.
+    pub started_at: u64,
+    /// Every file the config was read from, starting with the root.
+    pub config_files: Vec<PathBuf>,
+    /// A hash of the config files' contents as they were when last loaded.
+    pub config_hash: String,
+    pub threads: usize,
+    /// Jobs running on the thread pool. Every running system holds a thread.
+    pub active_jobs: usize,
+    /// Jobs waiting for a free thread.
+    pub queued_jobs: usize,
+    /// Output entries waiting to be written to the log.
+    pub queue_depth: usize,
+    /// When the output queue was last written out, in unix seconds.
+    pub last_flush: Option<u64>,
+    pub systems: Vec<SystemStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemStatus {
+    pub name: String,
+    pub running: bool,
+    /// Started, but waiting for a free thread in the pool.
+    pub queued: bool,
+    /// Stopped, but still finishing its current stage.
+    pub stopping: bool,
+    pub next_due: Option<NextDue>,
+}
+
+/// The next thing a system will run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NextDue {
+    /// `stage <id>` or `pipeline`, or a retry or catch-up run of one.
+    pub what: String,
+    /// Unix seconds.
+    pub at: u64,
+}
+
+/// A unix timestamp as local time, with how far away it is, e.g. `2024-05-01 09:00:00 (in 5m)`.
+pub fn format_time(secs: u64) -> String {
+    let time = chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| secs.to_string());
+
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    if secs > now {
+        format!("{} (in {})", time, format_duration(Duration::from_secs(secs - now)))
+    } else if secs == now {
+        format!("{} (now)", time)
+    } else {
+        format!("{} ({} ago)", time, format_duration(Duration::from_secs(now - secs)))
+    }
+}
+
+impl Display for ScheduleInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.schedule)?;
+        match self.next_run {
+            Some(next_run) => write!(f, "\n    next run: {}", format_time(next_run))?,
+            None => write!(f, "\n    next run: -")?,
+        }
+        match &self.last_run {
+            Some(last_run) => write!(
+                f,
+                "\n    last run: {}, {}",
+                format_time(last_run.at),
+                last_run.outcome
+            ),
+            None => write!(f, "\n    last run: never"),
+        }
+    }
+}
+
+impl Display for SystemInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "System: {}", self.name)?;
+        writeln!(f, "Description: {}", self.description)?;
+        writeln!(f, "Shell: {}", self.shell)?;
+        writeln!(f, "Mode: {}", self.mode)?;
+        write!(
+            f,
+            "Status: {}",
+            if self.running { "running" } else { "stopped" }
+        )?;
+        if let Some(pipeline) = &self.pipeline {
+            write!(f, "\nSchedule: {}", pipeline)?;
+        }
+        for stage in &self.stages {
+            write!(f, "\nStage {}: {}", stage.id, stage.command)?;
+            if let Some(schedule) = &stage.schedule {
+                write!(f, "\n  schedule: {}", schedule)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for CommandInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Command: {}", self.name)?;
+        writeln!(f, "Description: {}", self.description)?;
+        write!(f, "Shell: {}", self.shell)?;
+        for (index, stage) in self.stages.iter().enumerate() {
+            write!(f, "\nStage {}: {}", index + 1, stage)?;
+        }
+        for arg in &self.args {
+            write!(f, "\nArgument: {}", arg.synopsis())?;
+            if arg.required {
+                write!(f, " (required)")?;
+            }
+            if let Some(default) = &arg.default {
+                write!(f, " (default: {})", default)?;
+            }
+            if !arg.description.is_empty() {
+                write!(f, " - {}", arg.description)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let uptime = Duration::from_secs(self.now.saturating_sub(self.started_at));
+        writeln!(f, "Uptime: {}", format_duration(uptime))?;
+        for (index, file) in self.config_files.iter().enumerate() {
+            match index {
+                0 => writeln!(f, "Config: {}", file.display())?,
+                _ => writeln!(f, "        {}", file.display())?,
+            }
+        }
+        writeln!(f, "Config hash: {}", self.config_hash)?;
+        writeln!(
+            f,
+            "Thread pool: {} thread(s), {} active, {} queued",
+            self.threads, self.active_jobs, self.queued_jobs
+        )?;
+        writeln!(f, "Output queue: {} entries", self.queue_depth)?;
+        match self.last_flush {
+            Some(last_flush) => writeln!(f, "Last flush: {}", format_time(last_flush))?,
+            None => writeln!(f, "Last flush: never")?,
+        }
+
+        write!(f, "Systems:")?;
+        if self.systems.is_empty() {
+            write!(f, " none")?;
+        }
+        let width = self.systems.iter().map(|s| s.name.len()).max().unwrap_or(0);
+        for system in &self.systems {
+            write!(f, "\n  {:width$}  ", system.name, width = width)?;
+            match (&system.next_due, system.running) {
+                (_, false) => write!(f, "stopped")?,
+                _ if system.stopping => write!(f, "stopping after its current stage")?,
+                _ if system.queued => write!(f, "queued, waiting for a free thread")?,
+                (None, true) => write!(f, "running, nothing scheduled")?,
+                (Some(due), true) if due.at <= self.now => {
+                    write!(f, "running, {} is due now", due.what)?
+                }
+                (Some(due), true) => write!(
+                    f,
+                    "running, {} in {}",
+                    due.what,
+                    format_duration(Duration::from_secs(due.at - self.now))
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
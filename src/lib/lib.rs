@@ -1,46 +1,90 @@
 use std::{
-    fs::{File, OpenOptions}, io::Write, path::PathBuf, sync::{Arc, Mutex, RwLock}, task::Context, time::{Duration, SystemTime, UNIX_EPOCH}
+    collections::BTreeMap, fmt::{Display, Formatter}, fs::File, io::Write, path::{Path, PathBuf}, sync::{mpsc::{self, Sender}, Arc, Mutex, RwLock}, time::{Duration, Instant, UNIX_EPOCH}
 };
 
+use command_args::ParsedArgs;
 use command_builder::CommandBuilder;
-use priority::queue::{prelude::Prio, Queue};
+use config::{ConfigError, Source};
+use info::{Listing, Status, SystemStatus};
+use logs::LogWriter;
+use output::{OutputEntry, OutputQueue};
+use paths::Paths;
+use protocol::DaemonError;
 use settings::Settings;
-use system_builder::SystemBuilder;
+use spool::Spool;
+use state::StateStore;
+use serde::{Deserialize, Serialize};
+use system_builder::{SystemBuilder, SystemHandle};
 use threadpool::ThreadPool;
 use tomlp::TomlParser;
+use writer::{FlushReport, OutputWriter, WriterRequest};
 
+pub mod args;
+pub mod cancel;
 pub mod color;
+pub mod command_args;
 pub mod command_builder;
 pub mod command_stage;
+pub mod config;
+pub mod cron;
+pub mod duration;
+pub mod environment;
+pub mod info;
+pub mod log_query;
+pub mod logs;
+pub mod output;
+pub mod paths;
+pub mod process;
+pub mod protocol;
+#[cfg(test)]
+mod scratch;
 pub mod settings;
+pub mod size;
+pub mod socket;
+pub mod spool;
+pub mod state;
 pub mod system_builder;
 pub mod system_stage;
+pub mod template;
 pub mod tomlp;
+pub mod writer;
 
 pub struct Toaster {
-    pub toaster_path: PathBuf,
-    pub toaster_log_path: PathBuf,
+    pub paths: Paths,
     pub commands: Option<Vec<(String, CommandBuilder)>>,
     pub systems: Option<Vec<(String, SystemBuilder)>>,
     pub settings: Settings,
+    pub state: StateStore,
+    /// Handles to the systems that have been started, by name.
+    pub running_systems: BTreeMap<String, SystemHandle>,
     pub thread_pool: Arc<Mutex<ThreadPool>>,
-    pub output_queue: Arc<RwLock<Queue<String>>>,
-    flush_output: Arc<RwLock<bool>>,
+    pub output_queue: OutputQueue,
+    /// Shared with the output writer, so a reload can change where entries are written.
+    pub log_writer: Arc<RwLock<LogWriter>>,
+    /// Sends requests to the output writer's thread.
+    flusher: Sender<WriterRequest>,
     pub last_flush_output: Arc<RwLock<Option<String>>>,
+    /// When the output queue was last written out, in unix seconds.
+    pub last_flush_at: Arc<RwLock<Option<u64>>>,
+    /// When the daemon started, in unix seconds.
+    pub started_at: u64,
+    /// Every file the config was read from, starting with the root.
+    pub config_files: Vec<PathBuf>,
+    /// A hash of the config files' contents as they were when last loaded.
+    pub config_hash: String,
 }
 
 impl Toaster {
-    pub fn new() -> Self {
-        let home_dir = PathBuf::from(std::env::var("HOME").unwrap());
-        let path = home_dir.join(".toaster");
-        let log_path = path.join("Logs");
+    /// Loads the config and starts the output writer, or reports everything wrong with the config.
+    pub fn new(paths: Paths) -> Result<Self, ConfigError> {
+        let log_path = &paths.logs;
 
-        println!("Config Path: {}", path.to_str().unwrap());
-        let toaster_toml = path.join("toaster.toml");
+        println!("Config Path: {}", paths.config.display());
+        let toaster_toml = &paths.config;
         if !toaster_toml.exists() {
             println!("Creating toaster.toml...");
             std::fs::create_dir_all(toaster_toml.parent().unwrap()).unwrap();
-            let mut file = File::create(&toaster_toml).expect("Failed to create toaster.toml");
+            let mut file = File::create(toaster_toml).expect("Failed to create toaster.toml");
             let a = r#"[settings]
 threads = 1
 default_row_length = 4
@@ -76,12 +120,12 @@ default_row_length = 4
 
 "#;
             file.write_all(a.as_bytes()).unwrap();
-            println!("Toaster config located at: {}", path.to_str().unwrap());
+            println!("Toaster config located at: {}", toaster_toml.display());
         }
 
         if !log_path.exists() {
             println!("Creating log directory...");
-            let res = std::fs::create_dir_all(log_path.to_owned());
+            let res = std::fs::create_dir_all(log_path);
             match res {
                 Ok(_) => {
                     println!("Log directory created.");
@@ -92,128 +136,112 @@ default_row_length = 4
             }
         }
 
-        let toml_content = std::fs::read_to_string(&toaster_toml)
-            .expect("Failed to read toaster.toml in the given directory.");
-        let tparser = TomlParser::new(toml_content.as_str());
-        let package = tparser.parse().unwrap();
+        let parser = TomlParser::load(toaster_toml);
+        let package = parser.parse()?;
         let threads = package.2.threads;
+        let log_writer = LogWriter::new(paths.logs.clone(), package.2.logs.clone());
+        let (output_queue, recovered) = match Spool::open(&paths.spool) {
+            Ok((spool, recovered)) => (OutputQueue::with_spool(spool), recovered),
+            Err(e) => {
+                eprintln!(
+                    "Failed to open {}, so queued output won't survive a crash: {}",
+                    paths.spool.display(),
+                    e
+                );
+                (OutputQueue::new(), vec![])
+            }
+        };
+
+        let (flusher, requests) = mpsc::channel();
+        output_queue.set_writer(flusher.clone(), package.2.logs.max_queue_size);
 
         let toaster = Self {
-            toaster_path: path,
-            toaster_log_path: log_path,
+            state: StateStore::new(paths.state.clone()),
+            paths,
             commands: package.1,
             systems: package.0,
             settings: package.2,
+            running_systems: BTreeMap::new(),
             thread_pool: Arc::new(Mutex::new(ThreadPool::new(threads))),
-            output_queue: Arc::new(RwLock::new(Queue::new())),
-            flush_output: Arc::new(RwLock::new(false)),
+            output_queue,
+            log_writer: Arc::new(RwLock::new(log_writer)),
+            flusher,
             last_flush_output: Arc::new(RwLock::new(None)),
+            last_flush_at: Arc::new(RwLock::new(None)),
+            started_at: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+            config_files: parser.files().to_vec(),
+            config_hash: config_hash(parser.sources()),
         };
 
+        if !recovered.is_empty() {
+            println!("Recovered {} queued entries from the last run.", recovered.len());
+        }
         toaster
             .output_queue
-            .write()
-            .unwrap()
-            .push(Prio::wlip("SYSTEM: Starting toaster...".to_string()));
+            .push("SYSTEM: Starting toaster...".to_string());
 
-        println!("Starting output watcher...");
-        toaster.output_watcher();
-        std::thread::sleep(Duration::from_millis(3));
-        toaster.flush_output();
-        return toaster;
+        println!("Starting output writer...");
+        // Entries left over from a crash are written first, stamped with when they were queued.
+        OutputWriter {
+            output_queue: toaster.output_queue.clone(),
+            log_writer: toaster.log_writer.clone(),
+            last_flush_output: toaster.last_flush_output.clone(),
+            last_flush_at: toaster.last_flush_at.clone(),
+            unwritten: recovered,
+            requests,
+        }
+        .spawn();
+        if let Err(e) = toaster.flush_output() {
+            eprintln!("{}", e);
+        }
+        Ok(toaster)
     }
 
-    fn output_watcher(&self) {
-        let flush_output_signal = self.flush_output.clone();
-        let output_queue = self.output_queue.clone();
-        let log_path = Arc::new(self.toaster_log_path.clone());
-        let last_flush_output = self.last_flush_output.clone();
-
-        std::thread::spawn(move || loop {
-            if *flush_output_signal.read().unwrap() {
-                let datetime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                let tod = chrono::DateTime::from_timestamp(datetime.as_secs() as i64, 0)
-                    .unwrap()
-                    .format("%Y-%m-%d")
-                    .to_string();
-                let file_path = log_path.join(format!("output-{}.log", tod));
-                if !file_path.exists() {
-                    File::create(&file_path).expect("Failed to create log file");
-                }
-                let mut file = OpenOptions::new()
-                    .append(true)
-                    .open(file_path)
-                    .expect("Failed to open log file");
-
-                let mut queue = output_queue.write().unwrap();
-                let copy_of_queue = queue.all();
-                
-                let mut text_to_write: Vec<String> = vec![];
-
-                for output in copy_of_queue {
-                    queue.pop_by_id(output.0.identifier);
-                    let time = output.0.timestamp;
-                    let hh_mm_ss = chrono::DateTime::from_timestamp(time as i64, 0)
-                        .unwrap()
-                        .format("%H:%M:%S")
-                        .to_string();
-
-                    let text = format!("{} :::: {}\n", hh_mm_ss, output.1);
-                    text_to_write.push(text);
-                }
-
-                let text_bytes = text_to_write.join("");
-                let text_bytes = text_bytes.as_bytes();
-                
-                let res = file.write_all(text_bytes);
-                match res {
-                    Ok(_) => {
-                        last_flush_output.write().unwrap().replace(text_to_write.join("\n"));
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to write to log file: {}", e);
-                    }
-                }
-                *flush_output_signal.write().unwrap() = false;
-            }
-            std::thread::sleep(Duration::from_millis(20));
-        });
+    /// Writes everything in the output queue to the log files, waiting until it's done.
+    pub fn flush_output(&self) -> Result<FlushReport, String> {
+        let (reply, result) = mpsc::channel();
+        self.flusher
+            .send(WriterRequest::Flush(reply))
+            .map_err(|_| "The output writer has stopped.".to_string())?;
+        result
+            .recv()
+            .map_err(|_| "The output writer has stopped.".to_string())?
     }
 
-    /// Writes out all outputs into the days log file
-    pub fn flush_output(&self) {
-        *self.flush_output.write().unwrap() = true;
-        std::thread::sleep(Duration::from_millis(20));
+    /// Looks up a loaded command by name.
+    pub fn get_command(&self, command: &str) -> Option<&CommandBuilder> {
+        self.commands
+            .as_ref()?
+            .iter()
+            .find(|(name, _)| name == command)
+            .map(|(_, command_builder)| command_builder)
     }
 
-    pub fn run_command(&self, command: &str) {
-        let command_builder = match self.commands {
-            Some(ref commands) => commands.iter().find(|(name, _)| name == command),
-            None => {
-                eprintln!("Error: No commands are loaded. Please check your configuration.");
-                return;
-            }
-        };
-
-        if command_builder.is_none() {
-            eprintln!(
-                "Error: Command '{}' not found. Please check if the command name is correct.",
-                command
-            );
-            return;
-        }
+    /// Finds a loaded command and checks `args` against it, returning a copy of the command
+    /// to run with the parsed arguments, so running it doesn't need the daemon's lock.
+    pub fn prepare_command(
+        &self,
+        name: &str,
+        args: &[String],
+    ) -> Result<(CommandBuilder, ParsedArgs), DaemonError> {
+        let command = self
+            .get_command(name)
+            .ok_or_else(|| DaemonError::CommandNotFound(name.to_string()))?;
+        let args = command.parse_args(args).map_err(|e| {
+            DaemonError::Failed(format!("{}; run `toaster help {}` for usage", e, name))
+        })?;
+        Ok((command.clone(), args))
+    }
 
-        if let Some((_, command_builder)) = command_builder {
-            command_builder.run();
-        } else {
-            eprintln!("Command '{}' not found.", command);
-        }
+    /// Starts a loaded system on the thread pool.
+    pub fn start_system(&mut self, system: &str) -> Result<(), String> {
+        self.spawn_system(system, None)
     }
 
-    pub fn start_system(&mut self, system: &str) {
+    /// Starts a loaded system, once `previous` has finished if it's given.
+    fn spawn_system(&mut self, system: &str, previous: Option<SystemHandle>) -> Result<(), String> {
         if self.systems.is_none() {
-            eprintln!("No systems found.");
-            return;
+            return Err("No systems found.".to_string());
         }
         if let Some((_, system_builder)) = self
             .systems
@@ -222,14 +250,106 @@ default_row_length = 4
             .iter()
             .find(|(name, _)| name == system)
         {
+            if let Some(handle) = self.running_systems.get(system).filter(|_| previous.is_none()) {
+                if !handle.is_stopped() && !handle.is_finished() {
+                    return Err(format!("System '{}' is already running.", system));
+                }
+                if !handle.is_finished() {
+                    return Err(format!(
+                        "System '{}' is still stopping; start it again once its current stage finishes.",
+                        system
+                    ));
+                }
+            }
+
             let system_builder = system_builder.clone();
             let output_queue = self.output_queue.clone();
+            let state = self.state.clone();
+            let handle = match previous {
+                Some(previous) => SystemHandle::after(previous),
+                None => SystemHandle::new(),
+            };
+            self.running_systems.insert(system.to_string(), handle.clone());
 
             self.thread_pool.lock().unwrap().execute(move || {
-                system_builder.start(output_queue);
+                system_builder.start(output_queue, state, handle);
             });
+            Ok(())
         } else {
-            eprintln!("System '{}' not found.", system);
+            Err(format!("System '{}' not found.", system))
+        }
+    }
+
+    /// Describes every loaded system and command, and whether each system is running.
+    pub fn listing(&self) -> Listing {
+        let systems = self.systems.as_deref().unwrap_or_default();
+        let commands = self.commands.as_deref().unwrap_or_default();
+
+        Listing {
+            systems: systems
+                .iter()
+                .map(|(name, system_builder)| {
+                    // A stopped system is still running until it finishes its current stage.
+                    let running = self
+                        .running_systems
+                        .get(name)
+                        .is_some_and(|handle| !handle.is_finished());
+                    system_builder.info(&self.state.load(name), running)
+                })
+                .collect(),
+            commands: commands
+                .iter()
+                .map(|(_, command_builder)| command_builder.info())
+                .collect(),
+        }
+    }
+
+    /// A snapshot of what the daemon is doing, for `toaster status`.
+    pub fn status(&self) -> Status {
+        let (threads, active_jobs, queued_jobs) = {
+            let thread_pool = self.thread_pool.lock().unwrap();
+            (
+                thread_pool.max_count(),
+                thread_pool.active_count(),
+                thread_pool.queued_count(),
+            )
+        };
+
+        Status {
+            now: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+            started_at: self.started_at,
+            config_files: self.config_files.clone(),
+            config_hash: self.config_hash.clone(),
+            threads,
+            active_jobs,
+            queued_jobs,
+            queue_depth: self.output_queue.len(),
+            last_flush: *self.last_flush_at.read().unwrap(),
+            systems: self
+                .listing()
+                .systems
+                .into_iter()
+                .map(|system| SystemStatus {
+                    queued: system.running
+                        && self
+                            .running_systems
+                            .get(&system.name)
+                            .is_some_and(|handle| !handle.is_started()),
+                    stopping: system.running
+                        && self
+                            .running_systems
+                            .get(&system.name)
+                            .is_some_and(|handle| handle.is_stopped()),
+                    // The loop knows about retries and catch-up runs the saved state doesn't.
+                    next_due: self
+                        .running_systems
+                        .get(&system.name)
+                        .filter(|handle| handle.is_started() && !handle.is_finished())
+                        .map_or_else(|| system.next_due(), |handle| handle.next_due()),
+                    name: system.name,
+                    running: system.running,
+                })
+                .collect(),
         }
     }
 
@@ -251,17 +371,181 @@ default_row_length = 4
         let systems = self.systems_names();
 
         for system in systems {
-            self.start_system(system.as_str());
+            if let Err(e) = self.start_system(system.as_str()) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    /// Stops a running system once it finishes its current stage.
+    /// The handle is kept until the system has finished, so it can't be started twice meanwhile.
+    pub fn stop_system(&mut self, system: &str) -> Result<(), String> {
+        match self.running_systems.get(system) {
+            Some(handle) if !handle.is_stopped() => {
+                handle.stop();
+                Ok(())
+            }
+            _ => Err(format!("System '{}' isn't running.", system)),
+        }
+    }
+
+    /// Stops every system, giving them `grace` to finish their current stage before their
+    /// processes are killed, then flushes the output queue.
+    /// Returns the names of the systems that had to be killed.
+    pub fn shutdown(&mut self, grace: Duration) -> Vec<String> {
+        let handles = std::mem::take(&mut self.running_systems);
+        for handle in handles.values() {
+            handle.stop();
+        }
+
+        let deadline = Instant::now() + grace;
+        let mut killed = vec![];
+        for (name, handle) in &handles {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !handle.wait_finished(remaining) {
+                println!("System '{}' didn't stop in time; killing it.", name);
+                handle.kill();
+                killed.push(name.clone());
+            }
+        }
+
+        // Give killed systems a moment to record their last run.
+        for name in &killed {
+            handles[name].wait_finished(Duration::from_secs(1));
         }
+
+        self.output_queue
+            .push("SYSTEM: Shutting down toaster...".to_string());
+        if let Err(e) = self.flush_output() {
+            eprintln!("{}", e);
+        }
+        killed
     }
 
-    pub fn reload(&mut self) {
-        let path = self.toaster_path.clone();
-        let tomlp = TomlParser::new(path.to_str().unwrap());
-        let package = tomlp.parse().unwrap();
-        self.commands = package.1;
-        self.systems = package.0;
+    /// Re-reads the config and restarts whichever systems were added or changed.
+    /// Systems whose definition didn't change keep running untouched.
+    pub fn reload(&mut self) -> Result<ReloadSummary, String> {
+        let parser = TomlParser::load(&self.paths.config);
+        let package = parser.parse().map_err(|e| e.to_string())?;
+        self.config_files = parser.files().to_vec();
+        self.config_hash = config_hash(parser.sources());
+
+        let old_systems = self.systems.take().unwrap_or_default();
+        let new_systems = package.0.unwrap_or_default();
+        let old_commands = self.commands.take().unwrap_or_default();
+        let new_commands = package.1.unwrap_or_default();
+
+        let systems = diff_by_name(&old_systems, &new_systems);
+        let commands = diff_by_name(&old_commands, &new_commands);
+
+        for name in systems.removed.iter().chain(&systems.changed) {
+            println!("Stopping system '{}'...", name);
+            let _ = self.stop_system(name);
+        }
+
+        self.systems = Some(new_systems);
+        self.commands = Some(new_commands);
+        if self.settings.threads != package.2.threads {
+            self.thread_pool
+                .lock()
+                .unwrap()
+                .set_num_threads(package.2.threads);
+        }
+        self.log_writer.write().unwrap().settings = package.2.logs.clone();
+        self.output_queue.set_max_len(package.2.logs.max_queue_size);
         self.settings = package.2;
-        self.thread_pool = Arc::new(Mutex::new(ThreadPool::new(self.settings.threads)));
+
+        for name in systems.added.iter().chain(&systems.changed) {
+            println!("Starting system '{}'...", name);
+            // A system that's still finishing its last stage hands over once it's done.
+            let previous = self
+                .running_systems
+                .get(name)
+                .filter(|handle| !handle.is_finished())
+                .cloned();
+            if let Err(e) = self.spawn_system(name, previous) {
+                eprintln!("{}", e);
+            }
+        }
+
+        let summary = ReloadSummary { systems, commands };
+        self.output_queue.push(OutputEntry::Message(format!(
+            "SYSTEM: Reloaded config. {}",
+            summary
+        )));
+        Ok(summary)
     }
 }
+
+/// What changed in one kind of config entry during a reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReloadDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl Display for ReloadDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        for (label, names) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ] {
+            if !names.is_empty() {
+                parts.push(format!("{} {}", label, names.join(", ")));
+            }
+        }
+        parts.push(format!("{} unchanged", self.unchanged.len()));
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+/// Sent back to the client after a reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReloadSummary {
+    pub systems: ReloadDiff,
+    pub commands: ReloadDiff,
+}
+
+impl Display for ReloadSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Systems: {}. Commands: {}.", self.systems, self.commands)
+    }
+}
+
+/// Hashes the config files as they were parsed with 64-bit FNV-1a, so it's stable across builds.
+fn config_hash(sources: &[Arc<Source>]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for source in sources {
+        let file = source.file.as_deref().unwrap_or(Path::new(""));
+        let name = file.to_string_lossy();
+        for byte in name.bytes().chain([0]).chain(source.content.bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+fn diff_by_name<T: PartialEq>(old: &[(String, T)], new: &[(String, T)]) -> ReloadDiff {
+    let mut diff = ReloadDiff::default();
+
+    for (name, old_entry) in old {
+        match new.iter().find(|(new_name, _)| new_name == name) {
+            None => diff.removed.push(name.clone()),
+            Some((_, new_entry)) if new_entry != old_entry => diff.changed.push(name.clone()),
+            Some(_) => diff.unchanged.push(name.clone()),
+        }
+    }
+
+    for (name, _) in new {
+        if !old.iter().any(|(old_name, _)| old_name == name) {
+            diff.added.push(name.clone());
+        }
+    }
+
+    diff
+}
@@ -0,0 +1,381 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    duration::parse_duration,
+    logs::{format_line, log_files, LogFile, LogFormat, LogRecord},
+    output::OutputEntry,
+};
+
+/// An entry read back from the logs, or sent by the daemon while following them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedEntry {
+    /// Unix milliseconds.
+    pub time: i64,
+    /// The system the entry is about, if any.
+    pub system: Option<String>,
+    pub failed: bool,
+    /// The entry as it's written in the log, without its last newline.
+    pub text: String,
+}
+
+impl LoggedEntry {
+    /// The entries the log writer writes for one queued at `secs`, as they'll be read back.
+    /// A pipeline is written as a record for each stage and one for itself in `jsonl`.
+    pub fn from_entry(secs: u64, entry: &OutputEntry, format: LogFormat) -> Vec<Self> {
+        // Text logs are named and stamped in UTC.
+        let date = DateTime::from_timestamp(secs as i64, 0)
+            .unwrap_or_default()
+            .date_naive();
+        let text = format_line(secs, entry, format);
+        read_lines(date, format, text.lines().map(str::to_string))
+    }
+
+    /// Reads a `jsonl` line.
+    fn from_json(line: &str) -> Option<Self> {
+        let record: LogRecord = serde_json::from_str(line).ok()?;
+        Some(Self {
+            time: DateTime::parse_from_rfc3339(&record.timestamp)
+                .ok()?
+                .timestamp_millis(),
+            failed: record.priority == "error",
+            system: record.system,
+            text: line.to_string(),
+        })
+    }
+
+    /// Starts reading a text entry from its first line, like `09:00:00 :::: backup: Stage 1 ok`.
+    /// Text logs are stamped in UTC, on the day the file is named for.
+    fn from_text(date: NaiveDate, line: &str) -> Option<Self> {
+        let (time, text) = line.split_once(" :::: ")?;
+        let time = NaiveTime::parse_from_str(time, "%H:%M:%S").ok()?;
+        let system = text
+            .split_once(": ")
+            .map(|(system, _)| system)
+            .filter(|system| *system != "SYSTEM" && !system.contains(' '));
+
+        Some(Self {
+            time: Utc
+                .from_utc_datetime(&NaiveDateTime::new(date, time))
+                .timestamp_millis(),
+            system: system.map(str::to_string),
+            failed: ["FAILED", "TIMED OUT", "KILLED"]
+                .iter()
+                .any(|word| text.contains(word)),
+            text: line.to_string(),
+        })
+    }
+}
+
+/// Which entries `toaster logs` shows.
+#[derive(Debug, Clone, Default)]
+pub struct LogQuery {
+    pub system: Option<String>,
+    /// Unix milliseconds.
+    pub since: Option<i64>,
+    /// Unix milliseconds.
+    pub until: Option<i64>,
+    /// Text the entry has to contain.
+    pub grep: Option<String>,
+    pub failed_only: bool,
+}
+
+impl LogQuery {
+    pub fn matches(&self, entry: &LoggedEntry) -> bool {
+        self.system
+            .as_ref()
+            .is_none_or(|system| entry.system.as_ref() == Some(system))
+            && self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time <= until)
+            && self
+                .grep
+                .as_ref()
+                .is_none_or(|grep| entry.text.contains(grep))
+            && (!self.failed_only || entry.failed)
+    }
+
+    /// Whether a file from `date` could hold matching entries. A day either side is allowed,
+    /// since a stage's record is stamped with when it started rather than when it was queued.
+    fn covers(&self, date: NaiveDate) -> bool {
+        let day =
+            |millis: i64| DateTime::from_timestamp_millis(millis).map(|time| time.date_naive());
+        let after_since = self
+            .since
+            .and_then(day)
+            .is_none_or(|since| date >= since.pred_opt().unwrap_or(since));
+        let before_until = self
+            .until
+            .and_then(day)
+            .is_none_or(|until| date <= until.succ_opt().unwrap_or(until));
+        after_since && before_until
+    }
+}
+
+/// Reads every entry matching `query` from the log files under `dir`, oldest first.
+pub fn read_logs(dir: &Path, query: &LogQuery) -> Vec<LoggedEntry> {
+    let mut files = log_files(dir)
+        .into_iter()
+        .filter(|file| query.covers(file.date))
+        .collect::<Vec<LogFile>>();
+    files.sort_by_key(|file| {
+        (
+            file.date,
+            file.sequence.unwrap_or(u32::MAX),
+            file.path.clone(),
+        )
+    });
+
+    let mut entries = vec![];
+    for file in files {
+        match read_file(&file) {
+            Ok(read) => entries.extend(read.into_iter().filter(|entry| query.matches(entry))),
+            Err(e) => eprintln!("Failed to read {}: {}", file.path.display(), e),
+        }
+    }
+    // Routed logs are spread over several directories, so put them back in order.
+    entries.sort_by_key(|entry| entry.time);
+    entries
+}
+
+/// Reads the entries in a log file, in either format.
+fn read_file(file: &LogFile) -> std::io::Result<Vec<LoggedEntry>> {
+    let input: Box<dyn Read> = match file.compressed {
+        // Late entries for a compressed day are appended to it as another gzip member.
+        true => Box::new(MultiGzDecoder::new(File::open(&file.path)?)),
+        false => Box::new(File::open(&file.path)?),
+    };
+
+    let lines = BufReader::new(input)
+        .lines()
+        .collect::<std::io::Result<Vec<String>>>()?;
+    Ok(read_lines(file.date, file.format, lines))
+}
+
+/// Reads entries from the lines of a file from `date` written in `format`.
+fn read_lines(
+    date: NaiveDate,
+    format: LogFormat,
+    lines: impl IntoIterator<Item = String>,
+) -> Vec<LoggedEntry> {
+    let mut entries: Vec<LoggedEntry> = vec![];
+    for line in lines {
+        if format == LogFormat::Jsonl {
+            entries.extend(LoggedEntry::from_json(&line));
+        } else if let Some(entry) = LoggedEntry::from_text(date, &line) {
+            entries.push(entry);
+        } else if let Some(entry) = entries.last_mut() {
+            // A stage's output is written on the lines after it.
+            entry.text.push('\n');
+            entry.text.push_str(&line);
+        }
+    }
+    entries
+}
+
+/// Adds the entries that were still queued when following started to those read from the logs.
+///
+/// The queue can be flushed between the two, so each queued entry takes the place of one
+/// with the same time and text read from the logs, if there is one. Entries that are really
+/// logged twice are kept twice.
+pub fn merge_queued(entries: &mut Vec<LoggedEntry>, queued: Vec<LoggedEntry>) {
+    for entry in &queued {
+        let flushed = entries
+            .iter()
+            .rposition(|logged| logged.time == entry.time && logged.text == entry.text);
+        if let Some(index) = flushed {
+            entries.remove(index);
+        }
+    }
+    entries.extend(queued);
+    entries.sort_by_key(|entry| entry.time);
+}
+
+/// Reads a time for `--since` and `--until` as local time: `2024-05-01`, `2024-05-01 09:00`,
+/// `2024-05-01 09:00:00`, `09:00` for today, or a duration like `2h` for that long ago.
+/// Returns unix milliseconds.
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    let s = s.trim();
+    let local = |time: NaiveDateTime| {
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .map(|time| time.timestamp_millis())
+            .ok_or_else(|| format!("'{}' doesn't exist in the local timezone", s))
+    };
+
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(s, format) {
+            return local(time);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return local(date.and_time(NaiveTime::MIN));
+    }
+    for format in ["%H:%M:%S", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(s, format) {
+            return local(Local::now().date_naive().and_time(time));
+        }
+    }
+    if let Ok(ago) = parse_duration(s) {
+        return Ok(Local::now().timestamp_millis() - ago.as_millis() as i64);
+    }
+
+    Err(format!(
+        "Invalid time '{}'; expected a date like 2024-05-01 09:00, a time like 09:00, or a duration like 2h",
+        s
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_millis(s: &str) -> i64 {
+        let time = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    fn logged(time: i64, text: &str) -> LoggedEntry {
+        LoggedEntry {
+            time,
+            system: None,
+            failed: false,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_dates_and_times_as_local() {
+        let expected = local_millis("2024-05-01 09:30:15");
+        assert_eq!(parse_time("2024-05-01 09:30:15"), Ok(expected));
+        assert_eq!(parse_time(" 2024-05-01T09:30:15 "), Ok(expected));
+        assert_eq!(parse_time("2024-05-01 09:30"), Ok(expected - 15_000));
+        assert_eq!(
+            parse_time("2024-05-01"),
+            Ok(local_millis("2024-05-01 00:00:00"))
+        );
+
+        let today = Local::now().date_naive().format("%Y-%m-%d");
+        assert_eq!(
+            parse_time("09:30"),
+            Ok(local_millis(&format!("{} 09:30:00", today)))
+        );
+    }
+
+    #[test]
+    fn parses_durations_as_that_long_ago() {
+        let before = Local::now().timestamp_millis();
+        let time = parse_time("2h").unwrap();
+        let after = Local::now().timestamp_millis();
+        assert!((before - 7_200_000..=after - 7_200_000).contains(&time));
+    }
+
+    #[test]
+    fn rejects_other_times() {
+        for invalid in ["yesterday", "2024-13-01", "25:00", "2024-05-01 9am"] {
+            assert!(
+                parse_time(invalid).is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn reads_text_lines() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let expected_time = Utc
+            .with_ymd_and_hms(2024, 5, 1, 9, 0, 5)
+            .unwrap()
+            .timestamp_millis();
+
+        let entry =
+            LoggedEntry::from_text(date, "09:00:05 :::: backup: Stage 1 FAILED (exit 3)").unwrap();
+        assert_eq!(entry.time, expected_time);
+        assert_eq!(entry.system.as_deref(), Some("backup"));
+        assert!(entry.failed);
+
+        let entry = LoggedEntry::from_text(date, "09:00:05 :::: backup: Stage 1 ok").unwrap();
+        assert!(!entry.failed);
+
+        for line in [
+            "09:00:05 :::: SYSTEM: Reloaded",
+            "09:00:05 :::: Flushed 3 entries: ok",
+        ] {
+            assert_eq!(
+                LoggedEntry::from_text(date, line).unwrap().system,
+                None,
+                "{}",
+                line
+            );
+        }
+
+        for line in [
+            "",
+            "stage output",
+            "9am :::: backup: Stage 1 ok",
+            "09:00:05 backup",
+        ] {
+            assert!(LoggedEntry::from_text(date, line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn queued_entries_read_back_as_they_are_logged() {
+        for format in [LogFormat::Text, LogFormat::Jsonl] {
+            let entry = OutputEntry::Message("Stage output\n\nafter a blank line".to_string());
+            let secs = 1_714_554_005;
+            let text = format_line(secs, &entry, format);
+            let queued = LoggedEntry::from_entry(secs, &entry, format);
+            let read = read_lines(
+                NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+                format,
+                text.lines().map(str::to_string),
+            );
+
+            assert_eq!(queued.len(), 1);
+            assert_eq!(queued[0].time, secs as i64 * 1000);
+            assert_eq!(read.len(), 1);
+            assert_eq!(
+                (queued[0].time, &queued[0].text),
+                (read[0].time, &read[0].text)
+            );
+        }
+    }
+
+    #[test]
+    fn text_output_that_looks_like_json_stays_with_its_entry() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let lines = ["09:00:05 :::: backup: Stage 1 ok", "{\"rows\": 3}"];
+        let read = read_lines(date, LogFormat::Text, lines.map(str::to_string));
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].text, lines.join("\n"));
+    }
+
+    #[test]
+    fn queued_entries_replace_one_flushed_copy_each() {
+        let mut entries = vec![logged(1, "a"), logged(2, "b"), logged(2, "b")];
+        merge_queued(&mut entries, vec![logged(2, "b"), logged(3, "c")]);
+        let merged = entries
+            .iter()
+            .map(|entry| (entry.time, entry.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(merged, [(1, "a"), (2, "b"), (2, "b"), (3, "c")]);
+
+        // Only entries from the same second are the same entry.
+        let mut entries = vec![logged(1000, "a")];
+        merge_queued(&mut entries, vec![logged(2000, "a")]);
+        assert_eq!(entries.len(), 2);
+    }
+}
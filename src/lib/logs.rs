@@ -0,0 +1,586 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use chrono::{NaiveDate, SecondsFormat, TimeZone};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::output::{OutputEntry, StageRecord};
+
+/// Which files log entries are written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LogRoute {
+    /// Everything goes into one file in the log directory.
+    #[default]
+    Combined,
+    /// Each system gets its own directory.
+    System,
+    /// Each stage of each system gets its own directory.
+    Stage,
+}
+
+impl TryFrom<String> for LogRoute {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "combined" => Ok(LogRoute::Combined),
+            "system" => Ok(LogRoute::System),
+            "stage" => Ok(LogRoute::Stage),
+            _ => Err(format!(
+                "invalid route '{}'; expected combined, system or stage",
+                s
+            )),
+        }
+    }
+}
+
+/// How entries are written to the log files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LogFormat {
+    /// `HH:MM:SS :::: <entry>`, with a stage's output on the lines after it.
+    #[default]
+    Text,
+    /// One JSON `LogRecord` per line.
+    Jsonl,
+}
+
+impl LogFormat {
+    /// The extension of files written in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "log",
+            LogFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl TryFrom<String> for LogFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "jsonl" => Ok(LogFormat::Jsonl),
+            _ => Err(format!(
+                "invalid log format '{}'; expected text or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// A line of a `jsonl` log. Every field is always present, set to `null` when it doesn't apply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// RFC 3339 in local time, e.g. `2024-05-01T09:00:00.123+02:00`.
+    /// A stage's record is stamped with when it started, anything else with when it was queued.
+    pub timestamp: String,
+    /// `info`, `warning` or `error`.
+    pub priority: String,
+    pub system: Option<String>,
+    pub stage: Option<u8>,
+    pub command: Option<String>,
+    /// How the stage or pipeline ended, e.g. `ok` or `FAILED (exit 3)`.
+    pub status: Option<String>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: Option<u64>,
+    pub attempt: Option<u32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    /// The text of a message, or a pipeline's summary.
+    pub message: Option<String>,
+}
+
+impl LogRecord {
+    fn new(timestamp: String, priority: &str) -> Self {
+        Self {
+            timestamp,
+            priority: priority.to_string(),
+            system: None,
+            stage: None,
+            command: None,
+            status: None,
+            exit_code: None,
+            signal: None,
+            duration_ms: None,
+            attempt: None,
+            stdout: None,
+            stderr: None,
+            message: None,
+        }
+    }
+
+    fn stage(record: &StageRecord) -> Self {
+        let priority = if record.is_failure() { "error" } else { "info" };
+
+        Self {
+            system: Some(record.system.clone()),
+            stage: Some(record.stage),
+            command: Some(record.command.clone()),
+            status: Some(record.status()),
+            exit_code: record.exit_code,
+            signal: record.signal,
+            duration_ms: Some(record.duration_ms),
+            attempt: Some(record.attempt),
+            stdout: Some(record.stdout.clone()),
+            stderr: Some(record.stderr.clone()),
+            ..Self::new(timestamp_millis(record.started_at), priority)
+        }
+    }
+
+    /// The records an entry queued at `secs` is written as. A pipeline is written as a
+    /// record for each stage it ran, followed by one summing up the run.
+    pub fn from_entry(secs: u64, entry: &OutputEntry) -> Vec<LogRecord> {
+        let timestamp = timestamp_millis(secs as i64 * 1000);
+
+        match entry {
+            OutputEntry::Message(message) => vec![LogRecord {
+                message: Some(message.clone()),
+                ..Self::new(timestamp, "info")
+            }],
+            OutputEntry::SystemMessage { system, message } => vec![LogRecord {
+                system: Some(system.clone()),
+                message: Some(message.clone()),
+                ..Self::new(timestamp, "warning")
+            }],
+            OutputEntry::Stage(record) => vec![Self::stage(record)],
+            OutputEntry::Pipeline(record) => {
+                let mut records = record
+                    .stages
+                    .iter()
+                    .chain(&record.cleanup)
+                    .map(Self::stage)
+                    .collect::<Vec<LogRecord>>();
+                let priority = if record.is_failure() { "error" } else { "info" };
+                let summary = match record.failed_stage {
+                    Some(stage) => format!("Pipeline FAILED at stage {}", stage),
+                    None => "Pipeline ok".to_string(),
+                };
+                records.push(LogRecord {
+                    system: Some(record.system.clone()),
+                    status: Some(record.outcome().to_string()),
+                    duration_ms: Some(record.duration_ms),
+                    message: Some(summary),
+                    ..Self::new(timestamp_millis(record.started_at), priority)
+                });
+                records
+            }
+        }
+    }
+}
+
+/// How often the output queue is written out unless `flush_interval` says otherwise.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(45);
+/// How many entries can wait in the output queue unless `max_queue_size` says otherwise.
+pub const DEFAULT_MAX_QUEUE_SIZE: usize = 1000;
+
+/// `[settings.logs]`: where entries are written, when files are rotated and how long they're kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub route: LogRoute,
+    /// Set with `log_format` in `[settings]`.
+    pub format: LogFormat,
+    /// Rotate a day's file once it grows past this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Gzip files once they're rotated.
+    pub compress: bool,
+    /// Delete files whose day is further back than this.
+    pub max_age: Option<Duration>,
+    /// Delete the oldest files while all of them together take more than this many bytes.
+    pub max_total_size: Option<u64>,
+    /// Delete the oldest files while there are more than this many.
+    pub max_files: Option<usize>,
+    /// Write the output queue out this often.
+    pub flush_interval: Duration,
+    /// Write the output queue out as soon as this many entries are waiting.
+    pub max_queue_size: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            route: LogRoute::default(),
+            format: LogFormat::default(),
+            max_file_size: None,
+            compress: false,
+            max_age: None,
+            max_total_size: None,
+            max_files: None,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            max_queue_size: DEFAULT_MAX_QUEUE_SIZE,
+        }
+    }
+}
+
+/// Writes log entries to dated files under the log directory and keeps their number in check.
+///
+/// Files are named `output-YYYY-MM-DD.log`, or `.jsonl` for `jsonl`, so each day starts a new file.
+/// A file that's rotated for its size becomes `output-YYYY-MM-DD.N.log`, and `.gz` is added once
+/// it's compressed.
+#[derive(Debug, Clone)]
+pub struct LogWriter {
+    pub dir: PathBuf,
+    pub settings: LogSettings,
+}
+
+impl LogWriter {
+    pub fn new(dir: PathBuf, settings: LogSettings) -> Self {
+        Self { dir, settings }
+    }
+
+    /// Appends each entry, timestamped in unix seconds, to the file its route picks.
+    /// Returns how many bytes were written.
+    pub fn write(&self, entries: &[(u64, OutputEntry)]) -> std::io::Result<u64> {
+        let mut files: BTreeMap<PathBuf, String> = BTreeMap::new();
+        for (time, entry) in entries {
+            let name = format!(
+                "output-{}.{}",
+                log_date(*time),
+                self.settings.format.extension()
+            );
+            let path = self.entry_dir(entry).join(name);
+            files.entry(path).or_default().push_str(&format_line(
+                *time,
+                entry,
+                self.settings.format,
+            ));
+        }
+
+        let mut bytes = 0;
+        for (path, text) in files {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if let Some(max_file_size) = self.settings.max_file_size {
+                let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                if size > 0 && size + text.len() as u64 > max_file_size {
+                    rotate(&path)?;
+                }
+            }
+
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            file.write_all(text.as_bytes())?;
+            bytes += text.len() as u64;
+        }
+        Ok(bytes)
+    }
+
+    /// The directory an entry's file goes in. Pipelines are written whole to their system's
+    /// directory, and messages that don't belong to a system go in the log directory itself.
+    fn entry_dir(&self, entry: &OutputEntry) -> PathBuf {
+        let (system, stage) = match entry {
+            OutputEntry::Message(_) => return self.dir.clone(),
+            OutputEntry::SystemMessage { system, .. } => (system, None),
+            OutputEntry::Stage(record) => (&record.system, Some(record.stage)),
+            OutputEntry::Pipeline(record) => (&record.system, None),
+        };
+
+        match (self.settings.route, stage) {
+            (LogRoute::Combined, _) => self.dir.clone(),
+            (LogRoute::System, _) | (LogRoute::Stage, None) => self.dir.join(dir_name(system)),
+            (LogRoute::Stage, Some(stage)) => self
+                .dir
+                .join(dir_name(system))
+                .join(format!("stage-{}", stage)),
+        }
+    }
+
+    /// Compresses files that are no longer written to, then deletes the oldest files
+    /// until the retention limits are met. Today's files are never touched.
+    pub fn maintain(&self) {
+        let today = log_date(UNIX_EPOCH.elapsed().unwrap().as_secs());
+
+        if self.settings.compress {
+            for file in log_files(&self.dir) {
+                if !file.compressed && !file.is_active(&today) {
+                    if let Err(e) = compress(&file.path) {
+                        eprintln!("Failed to compress {}: {}", file.path.display(), e);
+                    }
+                }
+            }
+        }
+
+        let mut files = log_files(&self.dir);
+        files.sort_by_key(|file| (file.date, file.order()));
+
+        let oldest_kept = self.settings.max_age.and_then(|max_age| {
+            let cutoff = UNIX_EPOCH.elapsed().unwrap().saturating_sub(max_age);
+            NaiveDate::parse_from_str(&log_date(cutoff.as_secs()), "%Y-%m-%d").ok()
+        });
+        let mut count = files.len();
+        let mut total_size = files.iter().map(|file| file.size).sum::<u64>();
+
+        for file in files {
+            if file.is_active(&today) {
+                continue;
+            }
+            let too_old = oldest_kept.is_some_and(|oldest_kept| file.date < oldest_kept);
+            let too_many = self.settings.max_files.is_some_and(|max| count > max);
+            let too_big = self
+                .settings
+                .max_total_size
+                .is_some_and(|max| total_size > max);
+            if !too_old && !too_many && !too_big {
+                continue;
+            }
+
+            match std::fs::remove_file(&file.path) {
+                Ok(()) => {
+                    println!("Removed old log file {}", file.path.display());
+                    count -= 1;
+                    total_size -= file.size;
+                }
+                Err(e) => eprintln!("Failed to remove {}: {}", file.path.display(), e),
+            }
+        }
+    }
+}
+
+/// The day a log entry is filed under, as `YYYY-MM-DD`.
+pub fn log_date(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Unix milliseconds as an RFC 3339 timestamp in local time.
+fn timestamp_millis(millis: i64) -> String {
+    chrono::Local
+        .timestamp_millis_opt(millis)
+        .single()
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, false))
+        .unwrap_or_default()
+}
+
+/// An entry as it's written to the log, ending in a newline.
+pub fn format_line(secs: u64, entry: &OutputEntry, format: LogFormat) -> String {
+    match format {
+        LogFormat::Text => {
+            let hh_mm_ss = chrono::DateTime::from_timestamp(secs as i64, 0)
+                .unwrap()
+                .format("%H:%M:%S")
+                .to_string();
+            format!("{} :::: {}\n", hh_mm_ss, entry)
+        }
+        LogFormat::Jsonl => LogRecord::from_entry(secs, entry)
+            .iter()
+            .filter_map(|record| serde_json::to_string(record).ok())
+            .map(|json| json + "\n")
+            .collect(),
+    }
+}
+
+/// A system name made safe to use as a directory name.
+fn dir_name(system: &str) -> String {
+    system
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// Moves a day's file out of the way as `output-YYYY-MM-DD.N.log`, using the next free `N`.
+fn rotate(path: &Path) -> std::io::Result<()> {
+    let stem = path.with_extension("");
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let Some(current) = LogFile::parse(path.to_path_buf(), 0) else {
+        return Err(std::io::Error::other(format!(
+            "{} isn't a log file",
+            path.display()
+        )));
+    };
+    let next = log_files(dir)
+        .into_iter()
+        .filter(|file| file.path.parent() == Some(dir))
+        .filter(|file| file.date == current.date && file.format == current.format)
+        .filter_map(|file| file.sequence)
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    let extension = current.format.extension();
+    let rotated = PathBuf::from(format!("{}.{}.{}", stem.display(), next, extension));
+    std::fs::rename(path, rotated)
+}
+
+/// Gzips a file to `<name>.gz` and removes the original.
+///
+/// If `<name>.gz` already exists, like when entries for a day that was already compressed
+/// arrive late, the file is appended to it as another gzip member rather than replacing it.
+fn compress(path: &Path) -> std::io::Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+
+    let mut input = File::open(path)?;
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&gz_name)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(path)
+}
+
+/// A log file found under the log directory.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub date: NaiveDate,
+    /// The `N` of a file rotated for its size.
+    pub sequence: Option<u32>,
+    /// Which format the file was written in, going by its extension.
+    pub format: LogFormat,
+    pub compressed: bool,
+    pub size: u64,
+}
+
+impl LogFile {
+    /// Reads the date, sequence, format and compression from a name like
+    /// `output-2024-05-01.2.log.gz` or `output-2024-05-01.jsonl`.
+    fn parse(path: PathBuf, size: u64) -> Option<LogFile> {
+        let name = path.file_name()?.to_str()?;
+        let name = name.strip_prefix("output-")?;
+        let (name, compressed) = match name.strip_suffix(".gz") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (name, format) = match name.strip_suffix(".jsonl") {
+            Some(name) => (name, LogFormat::Jsonl),
+            None => (name.strip_suffix(".log")?, LogFormat::Text),
+        };
+        let (date, sequence) = match name.split_once('.') {
+            Some((date, sequence)) => (date, Some(sequence.parse().ok()?)),
+            None => (name, None),
+        };
+
+        Some(LogFile {
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?,
+            path,
+            sequence,
+            format,
+            compressed,
+            size,
+        })
+    }
+
+    /// Whether entries are still being written to the file.
+    pub fn is_active(&self, today: &str) -> bool {
+        self.sequence.is_none() && !self.compressed && self.date.to_string() == today
+    }
+
+    /// Orders a day's files from oldest to newest: rotated ones first, then the current one.
+    fn order(&self) -> u32 {
+        self.sequence.unwrap_or(u32::MAX)
+    }
+}
+
+/// Every log file in `dir` and the directories under it.
+pub fn log_files(dir: &Path) -> Vec<LogFile> {
+    let mut files = vec![];
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            files.extend(log_files(&entry.path()));
+        } else if let Some(file) = LogFile::parse(entry.path(), metadata.len()) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compressing_a_day_twice_keeps_both_files() {
+        let dir = ScratchDir::new("compress");
+        let path = dir.join("output-2024-05-01.log");
+
+        std::fs::write(&path, "first\n").unwrap();
+        compress(&path).unwrap();
+        std::fs::write(&path, "second\n").unwrap();
+        compress(&path).unwrap();
+
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(dir.join("output-2024-05-01.log.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "first\nsecond\n");
+    }
+
+    #[test]
+    fn reads_the_format_from_the_extension() {
+        let parse = |name: &str| LogFile::parse(PathBuf::from(name), 0);
+
+        let file = parse("output-2024-05-01.log").unwrap();
+        assert_eq!(
+            (file.format, file.sequence, file.compressed),
+            (LogFormat::Text, None, false)
+        );
+        let file = parse("output-2024-05-01.2.jsonl.gz").unwrap();
+        assert_eq!(
+            (file.format, file.sequence, file.compressed),
+            (LogFormat::Jsonl, Some(2), true)
+        );
+        assert_eq!(file.date, NaiveDate::from_ymd_opt(2024, 5, 1).unwrap());
+
+        for name in [
+            "output-2024-05-01.txt",
+            "output-2024-05-01",
+            "output.spool",
+            "state.json",
+        ] {
+            assert!(parse(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn jsonl_is_written_and_rotated_as_jsonl() {
+        let dir = ScratchDir::new("jsonl");
+        let settings = LogSettings {
+            format: LogFormat::Jsonl,
+            max_file_size: Some(1),
+            ..LogSettings::default()
+        };
+        let writer = LogWriter::new(dir.to_path_buf(), settings);
+        let secs = 1_714_554_005;
+        let entry = (secs, OutputEntry::Message("hello".to_string()));
+        writer.write(std::slice::from_ref(&entry)).unwrap();
+        writer.write(&[entry]).unwrap();
+
+        let mut names = log_files(&dir)
+            .into_iter()
+            .map(|file| file.path.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["output-2024-05-01.1.jsonl", "output-2024-05-01.jsonl"]
+        );
+    }
+}
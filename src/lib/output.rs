@@ -0,0 +1,355 @@
+use std::{
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use priority::queue::{prelude::Prio, Queue};
+use serde::{Deserialize, Serialize};
+
+use crate::{duration::format_duration, spool::Spool, state::StageOutcome, writer::WriterRequest};
+
+/// An entry with the unix second it was queued.
+pub type QueuedEntry = (u64, OutputEntry);
+
+/// The queue of entries waiting to be written to the log files.
+/// Clients following the log get each entry, with the unix second it was queued, as it's pushed.
+#[derive(Debug, Clone)]
+pub struct OutputQueue {
+    pub queue: Arc<RwLock<Queue<OutputEntry>>>,
+    followers: Arc<Mutex<Vec<Sender<QueuedEntry>>>>,
+    /// Keeps a copy of the queue on disk, when there is one.
+    spool: Arc<Mutex<Option<Spool>>>,
+    /// How many entries are queued, so checking doesn't need to copy the queue.
+    len: Arc<AtomicUsize>,
+    /// Told when the queue reaches `max_len`.
+    writer: Arc<Mutex<Option<Sender<WriterRequest>>>>,
+    max_len: Arc<AtomicUsize>,
+}
+
+impl OutputQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: Arc::new(RwLock::new(Queue::new())),
+            followers: Arc::new(Mutex::new(Vec::new())),
+            spool: Arc::new(Mutex::new(None)),
+            len: Arc::new(AtomicUsize::new(0)),
+            writer: Arc::new(Mutex::new(None)),
+            max_len: Arc::new(AtomicUsize::new(usize::MAX)),
+        }
+    }
+
+    /// A queue that appends every entry to `spool` before it's queued.
+    pub fn with_spool(spool: Spool) -> Self {
+        let output_queue = Self::new();
+        output_queue.spool.lock().unwrap().replace(spool);
+        output_queue
+    }
+
+    pub fn push(&self, entry: impl Into<OutputEntry>) {
+        let entry = entry.into();
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let mut queue = self.queue.write().unwrap();
+        if let Some(spool) = self.spool.lock().unwrap().as_mut() {
+            if let Err(e) = spool.append(&(now, entry.clone())) {
+                eprintln!("Failed to write to {}: {}", spool.path.display(), e);
+            }
+        }
+        queue.push(Prio::wlip(entry.clone()));
+        let len = self.len.fetch_add(1, Ordering::SeqCst) + 1;
+        if len == self.max_len.load(Ordering::SeqCst) {
+            self.wake_writer();
+        }
+
+        // Followers that have gone away are dropped the first time sending to them fails.
+        self.followers
+            .lock()
+            .unwrap()
+            .retain(|follower| follower.send((now, entry.clone())).is_ok());
+    }
+
+    /// Has `writer` told whenever `max_len` entries are waiting.
+    pub fn set_writer(&self, writer: Sender<WriterRequest>, max_len: usize) {
+        self.writer.lock().unwrap().replace(writer);
+        self.set_max_len(max_len);
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::SeqCst);
+        if self.len() >= max_len {
+            self.wake_writer();
+        }
+    }
+
+    fn wake_writer(&self) {
+        if let Some(writer) = self.writer.lock().unwrap().as_ref() {
+            let _ = writer.send(WriterRequest::QueueFull);
+        }
+    }
+
+    /// How many entries are waiting.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes every queued entry, oldest first.
+    pub fn drain(&self) -> Vec<QueuedEntry> {
+        let mut queue = self.queue.write().unwrap();
+        let mut entries = vec![];
+        for (meta, entry) in queue.all() {
+            queue.pop_by_id(meta.identifier);
+            entries.push((meta.timestamp, entry));
+        }
+        self.len.store(0, Ordering::SeqCst);
+        entries
+    }
+
+    /// Rewrites the spool to hold `unwritten`, entries taken from the queue that couldn't be
+    /// written, followed by whatever has been queued since.
+    pub fn compact_spool(&self, unwritten: &[QueuedEntry]) {
+        let queue = self.queue.read().unwrap();
+        let mut spool = self.spool.lock().unwrap();
+        let Some(spool) = spool.as_mut() else {
+            return;
+        };
+
+        let entries = unwritten
+            .iter()
+            .cloned()
+            .chain(
+                queue
+                    .all()
+                    .into_iter()
+                    .map(|(meta, entry)| (meta.timestamp, entry)),
+            )
+            .collect::<Vec<QueuedEntry>>();
+        if let Err(e) = spool.rewrite(&entries) {
+            eprintln!("Failed to rewrite {}: {}", spool.path.display(), e);
+        }
+    }
+
+    /// Starts following the queue. Returns what's queued right now, then every entry pushed after.
+    pub fn follow(&self) -> (Vec<QueuedEntry>, Receiver<QueuedEntry>) {
+        let (sender, receiver) = mpsc::channel();
+        // Holding the queue lock means no entry is pushed between the snapshot and subscribing.
+        let queue = self.queue.read().unwrap();
+        self.followers.lock().unwrap().push(sender);
+
+        let queued = queue
+            .all()
+            .into_iter()
+            .map(|(meta, entry)| (meta.timestamp, entry))
+            .collect();
+        (queued, receiver)
+    }
+}
+
+impl Default for OutputQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Something waiting in the output queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OutputEntry {
+    /// A free-form message from the daemon.
+    Message(String),
+    /// A free-form message about a system, like a retry being scheduled.
+    SystemMessage { system: String, message: String },
+    /// The result of running a system stage.
+    Stage(StageRecord),
+    /// The result of running a pipeline system's stages as one unit.
+    Pipeline(PipelineRecord),
+}
+
+impl From<String> for OutputEntry {
+    fn from(message: String) -> Self {
+        OutputEntry::Message(message)
+    }
+}
+
+impl Display for OutputEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputEntry::Message(message) => write!(f, "{}", message),
+            OutputEntry::SystemMessage { system, message } => write!(f, "{}: {}", system, message),
+            OutputEntry::Stage(record) => write!(f, "{}", record),
+            OutputEntry::Pipeline(record) => write!(f, "{}", record),
+        }
+    }
+}
+
+/// Everything known about a single run of a system stage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageRecord {
+    pub system: String,
+    pub stage: u8,
+    pub command: String,
+    /// Unix milliseconds.
+    pub started_at: i64,
+    pub duration_ms: u64,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    /// The signal that ended the process, if it didn't exit on its own.
+    pub signal: Option<i32>,
+    pub outcome: StageOutcome,
+    /// Which attempt this was, counting from 1.
+    pub attempt: u32,
+    /// How many attempts the stage's retry policy allows.
+    pub max_attempts: u32,
+}
+
+impl StageRecord {
+    pub fn is_failure(&self) -> bool {
+        self.outcome != StageOutcome::Success
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// A short description of how the stage ended, e.g. `ok` or `FAILED (exit 3)`.
+    pub fn status(&self) -> String {
+        match (&self.outcome, self.signal) {
+            // The outcome only keeps the exit code, which a process killed by a signal doesn't have.
+            (StageOutcome::Failed(None), Some(signal)) => format!("FAILED (signal {})", signal),
+            (outcome, _) => outcome.to_string(),
+        }
+    }
+}
+
+impl Display for StageRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: Stage {} {} after {}",
+            self.system,
+            self.stage,
+            self.status(),
+            format_duration(self.duration())
+        )?;
+        if self.max_attempts > 1 {
+            write!(f, " (attempt {} of {})", self.attempt, self.max_attempts)?;
+        }
+
+        for (label, text) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            for line in text.lines() {
+                write!(f, "\n    {} | {}", label, line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single run of a pipeline system.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineRecord {
+    pub system: String,
+    /// Unix milliseconds.
+    pub started_at: i64,
+    pub duration_ms: u64,
+    /// Every attempt of every stage that ran, in order.
+    pub stages: Vec<StageRecord>,
+    /// The first stage that failed, if any did.
+    pub failed_stage: Option<u8>,
+    /// The cleanup stage's final attempt, if the pipeline failed and had one.
+    pub cleanup: Option<StageRecord>,
+}
+
+impl PipelineRecord {
+    pub fn is_failure(&self) -> bool {
+        self.failed_stage.is_some()
+    }
+
+    /// The outcome of the pipeline as a whole: the outcome of its first failed stage, if any.
+    pub fn outcome(&self) -> StageOutcome {
+        let failed = self.failed_stage.and_then(|failed| {
+            self.stages
+                .iter()
+                .rev()
+                .find(|record| record.stage == failed)
+        });
+
+        match failed {
+            Some(record) => record.outcome.clone(),
+            None => StageOutcome::Success,
+        }
+    }
+}
+
+impl Display for PipelineRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.failed_stage {
+            Some(stage) => write!(f, "{}: Pipeline FAILED at stage {}", self.system, stage)?,
+            None => write!(f, "{}: Pipeline ok", self.system)?,
+        }
+        write!(
+            f,
+            " after {}",
+            format_duration(Duration::from_millis(self.duration_ms))
+        )?;
+
+        let cleanup = self.cleanup.iter().map(|record| ("Cleanup ", record));
+        for (label, record) in self.stages.iter().map(|record| ("", record)).chain(cleanup) {
+            let stage = record.to_string();
+            let stage = stage.strip_prefix(&format!("{}: ", self.system)).unwrap_or(&stage);
+            for (i, line) in stage.lines().enumerate() {
+                let label = if i == 0 { label } else { "" };
+                write!(f, "\n  {}{}", label, line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(outcome: StageOutcome, exit_code: Option<i32>, signal: Option<i32>) -> StageRecord {
+        StageRecord {
+            system: "backup".to_string(),
+            stage: 1,
+            command: "true".to_string(),
+            started_at: 0,
+            duration_ms: 0,
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code,
+            signal,
+            outcome,
+            attempt: 1,
+            max_attempts: 1,
+        }
+    }
+
+    #[test]
+    fn status_reads_like_the_outcome() {
+        for (outcome, exit_code) in [
+            (StageOutcome::Success, Some(0)),
+            (StageOutcome::Failed(Some(3)), Some(3)),
+            (StageOutcome::TimedOut, None),
+            (StageOutcome::Killed, None),
+            (StageOutcome::SpawnFailed("no such shell".to_string()), None),
+        ] {
+            let expected = outcome.to_string();
+            assert_eq!(record(outcome, exit_code, None).status(), expected);
+        }
+    }
+
+    #[test]
+    fn status_names_the_signal_a_failed_stage_died_of() {
+        let status = record(StageOutcome::Failed(None), None, Some(9)).status();
+        assert_eq!(status, "FAILED (signal 9)");
+    }
+}
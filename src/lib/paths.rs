@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+/// Environment variable pointing at a directory that holds the config, logs and state together.
+pub const HOME_ENV: &str = "TOASTER_HOME";
+
+/// Where Toaster keeps its config, logs and per-system state.
+///
+/// Both binaries resolve these the same way:
+/// - `--config <file>` picks the config file.
+/// - `TOASTER_HOME` holds everything, laid out like the legacy `~/.toaster`.
+/// - `~/.toaster` is used if it already exists.
+/// - Otherwise the config lives in `$XDG_CONFIG_HOME/toaster` and logs and state in `$XDG_STATE_HOME/toaster`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// The config file.
+    pub config: PathBuf,
+    /// The directory log files are written to.
+    pub logs: PathBuf,
+    /// The directory per-system state is kept in.
+    pub state: PathBuf,
+    /// The file the output queue is spooled to until it's written to the logs.
+    pub spool: PathBuf,
+}
+
+impl Paths {
+    /// Resolves the paths, using `config` from `--config` if it was given.
+    pub fn resolve(config: Option<String>) -> Result<Paths, String> {
+        let (home_config, logs, state, spool) = match toaster_home()? {
+            Some(home) => (
+                home.join("toaster.toml"),
+                home.join("Logs"),
+                home.join("state"),
+                home.join("output.spool"),
+            ),
+            None => {
+                let config_dir = xdg_dir("XDG_CONFIG_HOME", ".config")?.join("toaster");
+                let state_dir = xdg_dir("XDG_STATE_HOME", ".local/state")?.join("toaster");
+                (
+                    config_dir.join("toaster.toml"),
+                    state_dir.join("logs"),
+                    state_dir.join("systems"),
+                    state_dir.join("output.spool"),
+                )
+            }
+        };
+
+        let config = match config {
+            Some(config) => {
+                let config = PathBuf::from(config);
+                if !config.is_file() {
+                    return Err(format!("Config file {} doesn't exist", config.display()));
+                }
+                config
+            }
+            None => home_config,
+        };
+
+        Ok(Paths {
+            config,
+            logs,
+            state,
+            spool,
+        })
+    }
+
+    /// The directory the config file is in.
+    pub fn config_dir(&self) -> &Path {
+        self.config.parent().unwrap_or(Path::new("."))
+    }
+}
+
+/// `TOASTER_HOME`, or `~/.toaster` if it exists from before XDG directories were supported.
+fn toaster_home() -> Result<Option<PathBuf>, String> {
+    if let Some(home) = std::env::var_os(HOME_ENV).filter(|h| !h.is_empty()) {
+        return Ok(Some(PathBuf::from(home)));
+    }
+
+    let legacy = home_dir()?.join(".toaster");
+    Ok(legacy.is_dir().then_some(legacy))
+}
+
+/// The XDG directory in `var`, falling back to `fallback` under the home directory.
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf, String> {
+    match std::env::var_os(var).map(PathBuf::from) {
+        // The spec says relative paths are invalid and should be ignored.
+        Some(dir) if dir.is_absolute() => Ok(dir),
+        _ => Ok(home_dir()?.join(fallback)),
+    }
+}
+
+fn home_dir() -> Result<PathBuf, String> {
+    match std::env::var_os("HOME").filter(|h| !h.is_empty()) {
+        Some(home) => Ok(PathBuf::from(home)),
+        None => Err(format!(
+            "HOME isn't set; set {} to say where Toaster's files are",
+            HOME_ENV
+        )),
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    io::Read,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::cancel::CancelToken;
+
+/// How often a running process is checked for completion or cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The result of a process run through `run_process`.
+#[derive(Debug)]
+pub struct ProcessOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether the process was killed because `kill` was cancelled.
+    pub killed: bool,
+    /// Whether the process was killed because it ran past its timeout.
+    pub timed_out: bool,
+}
+
+/// Runs `command` to completion, capturing its output.
+///
+/// The process gets its own process group, and the whole group is killed if `kill` is
+/// cancelled or `timeout` expires while it's running, so anything a shell script started
+/// goes with it.
+pub fn run_process(
+    mut command: Command,
+    kill: &CancelToken,
+    timeout: Option<Duration>,
+) -> std::io::Result<ProcessOutput> {
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain both pipes on their own threads so a chatty process can't fill one and block.
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (status, ended) = wait_or_kill(&mut child, kill, deadline)?;
+
+    Ok(ProcessOutput {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+        killed: ended == Ended::Killed,
+        timed_out: ended == Ended::TimedOut,
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Ended {
+    Exited,
+    Killed,
+    TimedOut,
+}
+
+fn wait_or_kill(
+    child: &mut Child,
+    kill: &CancelToken,
+    deadline: Option<Instant>,
+) -> std::io::Result<(ExitStatus, Ended)> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, Ended::Exited));
+        }
+
+        if kill.wait_timeout(POLL_INTERVAL) {
+            kill_process_group(child);
+            return Ok((child.wait()?, Ended::Killed));
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill_process_group(child);
+            return Ok((child.wait()?, Ended::TimedOut));
+        }
+    }
+}
+
+/// Sends `SIGKILL` to every process in the child's process group.
+fn kill_process_group(child: &mut Child) {
+    // The child leads its own group, so its pid is the group id.
+    let pgid = child.id() as libc::pid_t;
+    // SAFETY: `kill` has no memory safety requirements.
+    let result = unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    if result != 0 {
+        // The group may already be gone; make sure the direct child is at least.
+        let _ = child.kill();
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
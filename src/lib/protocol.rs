@@ -0,0 +1,362 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    info::{Listing, Status},
+    log_query::LoggedEntry,
+    writer::FlushReport,
+    ReloadSummary,
+};
+
+/// Bumped whenever `Request` or `Response` change shape.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Largest frame either side is willing to read, in bytes.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Messages sent from the client to the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// First message on every connection.
+    Hello { version: u32 },
+    Ping,
+    Reload,
+    Flush,
+    /// Runs a `[command.<name>]` entry, streaming its output back.
+    Run { command: String, args: Vec<String> },
+    /// Asks for a command's description and arguments.
+    Help { command: String },
+    /// Asks for every loaded system and command.
+    List,
+    /// Asks what the daemon is doing.
+    Status,
+    /// Streams log entries as they're queued, until the client disconnects.
+    Follow,
+    /// Starts a system that isn't running.
+    Start { system: String },
+    /// Stops a running system once its current stage finishes.
+    Stop { system: String },
+    /// Stops every system, flushes the output queue and exits the daemon.
+    Shutdown,
+}
+
+/// Messages sent from the daemon to the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Hello { version: u32 },
+    Pong,
+    Ok,
+    Reloaded(ReloadSummary),
+    /// What was written to the log files, answering `Flush`.
+    Flushed(FlushReport),
+    /// The daemon is exiting; lists systems that had to be killed.
+    ShuttingDown { killed: Vec<String> },
+    /// A chunk of a running command's stdout.
+    Stdout(String),
+    /// A chunk of a running command's stderr.
+    Stderr(String),
+    /// A running command finished with this exit code.
+    Exit(i32),
+    /// Usage text for a command, answering `Help`.
+    Help(String),
+    /// Every loaded system and command, answering `List`.
+    List(Listing),
+    /// What the daemon is doing, answering `Status`.
+    Status(Status),
+    /// Entries queued but not yet written to the log, answering `Follow`.
+    Following(Vec<LoggedEntry>),
+    /// An entry that was just queued, sent to clients following the log.
+    Logged(LoggedEntry),
+    Error(DaemonError),
+}
+
+/// Errors the daemon reports back to the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DaemonError {
+    VersionMismatch { client: u32, daemon: u32 },
+    /// The first message on the connection wasn't a `Hello`.
+    HandshakeRequired,
+    /// The client is running as a different user than the daemon.
+    PermissionDenied,
+    CommandNotFound(String),
+    Failed(String),
+}
+
+impl Display for DaemonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DaemonError::VersionMismatch { client, daemon } => write!(
+                f,
+                "Protocol version mismatch: client speaks v{}, daemon speaks v{}",
+                client, daemon
+            ),
+            DaemonError::HandshakeRequired => write!(f, "Expected a handshake first"),
+            DaemonError::PermissionDenied => {
+                write!(f, "Permission denied; the daemon belongs to another user")
+            }
+            DaemonError::CommandNotFound(name) => write!(f, "Command '{}' not found", name),
+            DaemonError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Errors from reading or writing frames.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    FrameTooLarge(u32),
+    /// The other side closed the connection between frames.
+    Closed,
+    /// The daemon sent a response that doesn't fit the request.
+    Unexpected(Box<Response>),
+    Daemon(DaemonError),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "I/O error: {}", e),
+            ProtocolError::Encoding(e) => write!(f, "Malformed message: {}", e),
+            ProtocolError::FrameTooLarge(len) => write!(
+                f,
+                "Frame of {} bytes exceeds the {} byte limit",
+                len, MAX_FRAME_LEN
+            ),
+            ProtocolError::Closed => write!(f, "Connection closed"),
+            ProtocolError::Unexpected(response) => {
+                write!(f, "Unexpected response from daemon: {:?}", response)
+            }
+            ProtocolError::Daemon(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> Self {
+        ProtocolError::Encoding(e)
+    }
+}
+
+/// Writes `message` as a frame: a big-endian `u32` length followed by the bincode payload.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<(), ProtocolError> {
+    let payload = bincode::serialize(message)?;
+    let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads one frame written by `write_frame`.
+/// Returns `ProtocolError::Closed` if the stream ends before a new frame starts.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, ProtocolError> {
+    let mut len_bytes = [0; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(ProtocolError::Closed)
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len_bytes);
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+/// Performs the daemon's half of the handshake.
+/// On a version mismatch the client is told why before the error is returned.
+pub fn accept_handshake(stream: &mut UnixStream) -> Result<(), ProtocolError> {
+    let error = match read_frame::<_, Request>(stream)? {
+        Request::Hello { version } if version == PROTOCOL_VERSION => {
+            return write_frame(stream, &Response::Hello { version: PROTOCOL_VERSION });
+        }
+        Request::Hello { version } => DaemonError::VersionMismatch {
+            client: version,
+            daemon: PROTOCOL_VERSION,
+        },
+        _ => DaemonError::HandshakeRequired,
+    };
+
+    write_frame(stream, &Response::Error(error.clone()))?;
+    Err(ProtocolError::Daemon(error))
+}
+
+/// A client connection to the daemon.
+pub struct Connection {
+    pub stream: UnixStream,
+}
+
+impl Connection {
+    /// Connects to the daemon socket and performs the version handshake.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, ProtocolError> {
+        let stream = UnixStream::connect(path)?;
+        let mut connection = Self { stream };
+
+        match connection.request(&Request::Hello { version: PROTOCOL_VERSION })? {
+            Response::Hello { .. } => Ok(connection),
+            Response::Error(e) => Err(ProtocolError::Daemon(e)),
+            other => Err(ProtocolError::Unexpected(Box::new(other))),
+        }
+    }
+
+    /// Sends a request and waits for the first response.
+    pub fn request(&mut self, request: &Request) -> Result<Response, ProtocolError> {
+        write_frame(&mut self.stream, request)?;
+        self.recv()
+    }
+
+    /// Reads the next response, for requests that stream more than one.
+    pub fn recv(&mut self) -> Result<Response, ProtocolError> {
+        read_frame(&mut self.stream)
+    }
+}
+
+/// Which output stream a `FrameWriter` forwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Adapts a socket into a `Write` that sends everything as `Stdout` or `Stderr` frames.
+/// Writes are buffered and sent as one frame on `flush`, or once the buffer grows large.
+pub struct FrameWriter {
+    stream: UnixStream,
+    kind: OutputStream,
+    buffer: Vec<u8>,
+}
+
+impl FrameWriter {
+    const BUFFER_LIMIT: usize = 64 * 1024;
+
+    pub fn new(stream: UnixStream, kind: OutputStream) -> Self {
+        Self {
+            stream,
+            kind,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Sends the first `len` bytes of the buffer as a frame.
+    fn send(&mut self, len: usize) -> std::io::Result<()> {
+        let text = String::from_utf8_lossy(&self.buffer[..len]).to_string();
+        self.buffer.drain(..len);
+        let response = match self.kind {
+            OutputStream::Stdout => Response::Stdout(text),
+            OutputStream::Stderr => Response::Stderr(text),
+        };
+
+        write_frame(&mut self.stream, &response).map_err(|e| match e {
+            ProtocolError::Io(e) => e,
+            e => std::io::Error::other(e.to_string()),
+        })
+    }
+}
+
+/// How many bytes at the end of `bytes` are the start of a UTF-8 character that's cut short.
+fn incomplete_char_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // Continuation bytes are 0b10xxxxxx; look back for the byte that starts the character.
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let len = match byte {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if len > back { back } else { 0 };
+    }
+    0
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= Self::BUFFER_LIMIT {
+            // Keep a character that's cut short for the next frame, so it isn't sent as two
+            // replacement characters.
+            let len = self.buffer.len() - incomplete_char_len(&self.buffer);
+            self.send(len)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send(self.buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_characters_cut_short() {
+        let text = "aé€😀".as_bytes();
+        assert_eq!(incomplete_char_len(text), 0);
+        assert_eq!(incomplete_char_len(&text[..text.len() - 1]), 3);
+        assert_eq!(incomplete_char_len(&text[..text.len() - 3]), 1);
+        assert_eq!(incomplete_char_len(&text[..2]), 1);
+        assert_eq!(incomplete_char_len(b""), 0);
+        // Bytes that aren't UTF-8 at all are sent as they are.
+        assert_eq!(incomplete_char_len(&[0x80, 0x80, 0x80]), 0);
+    }
+
+    #[test]
+    fn characters_are_not_split_between_frames() {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        let text = format!("{}é€😀 done", "a".repeat(FrameWriter::BUFFER_LIMIT - 1));
+
+        let mut writer = FrameWriter::new(daemon, OutputStream::Stdout);
+        for byte in text.as_bytes() {
+            writer.write_all(std::slice::from_ref(byte)).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut client = client;
+        let mut received = String::new();
+        loop {
+            match read_frame::<_, Response>(&mut client) {
+                Ok(Response::Stdout(frame)) => received.push_str(&frame),
+                Ok(other) => panic!("unexpected response {:?}", other),
+                Err(ProtocolError::Closed) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(received, text);
+    }
+}
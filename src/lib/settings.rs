@@ -1,14 +1,26 @@
+use std::path::PathBuf;
+
+use crate::logs::LogSettings;
 
 #[derive(Debug, Clone)]
 pub struct Settings {
     pub threads: usize,
     pub default_row_length: usize,
+    /// Seconds the daemon waits for running stages to finish when shutting down.
+    pub shutdown_timeout: u64,
+    /// Where the daemon listens, unless `--socket` or `TOASTER_SOCKET` say otherwise.
+    pub socket: Option<PathBuf>,
+    pub logs: LogSettings,
 }
 
 impl Settings {
     pub fn new(threads: usize, default_row_length: usize) -> Self {
         Self {
-            threads, default_row_length
+            threads,
+            default_row_length,
+            shutdown_timeout: 10,
+            socket: None,
+            logs: LogSettings::default(),
         }
     }
-}
\ No newline at end of file
+}
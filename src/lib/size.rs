@@ -0,0 +1,35 @@
+use toml::Value;
+
+/// Parses a human readable size such as `"512KB"`, `"10MB"` or `"1GB"`.
+/// A bare number is taken as bytes. Units are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid size '{}'; expected something like 512KB, 10MB or 1GB", s);
+
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if digits == 0 {
+        return Err(invalid());
+    }
+    let amount = s[..digits].parse::<u64>().map_err(|_| invalid())?;
+
+    let unit = match s[digits..].trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    amount.checked_mul(unit).ok_or_else(invalid)
+}
+
+/// Reads a size from TOML, either an integer number of bytes or a string for `parse_size`.
+pub fn size_from_toml(value: &Value) -> Result<u64, String> {
+    match value {
+        Value::Integer(bytes) if *bytes >= 0 => Ok(*bytes as u64),
+        Value::String(s) => parse_size(s),
+        other => Err(format!(
+            "Invalid size {}; expected bytes or a string like \"10MB\"",
+            other
+        )),
+    }
+}
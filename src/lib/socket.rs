@@ -0,0 +1,115 @@
+use std::{
+    io,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+/// Environment variable that overrides where the daemon's socket is.
+pub const SOCKET_ENV: &str = "TOASTER_SOCKET";
+
+/// Works out where the daemon's socket is. The `--socket` flag wins, then `TOASTER_SOCKET`,
+/// then `socket` in `[settings]`, then the default.
+pub fn socket_path(flag: Option<String>, settings: Option<&Path>) -> PathBuf {
+    if let Some(path) = flag {
+        return PathBuf::from(path);
+    }
+    if let Some(path) = std::env::var_os(SOCKET_ENV).filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
+    match settings {
+        Some(path) => path.to_path_buf(),
+        None => default_socket_path(),
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/toaster.sock`, or a per-user socket in the temp dir when that isn't set.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|p| !p.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("toaster.sock"),
+        None => std::env::temp_dir().join(format!("toaster-{}.sock", current_uid())),
+    }
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: `geteuid` takes no arguments, always succeeds and has no memory safety requirements.
+    unsafe { libc::geteuid() }
+}
+
+/// Binds the daemon's socket so that only the current user can connect to it.
+///
+/// A stale socket left behind by a daemon that died is replaced, but a live daemon or
+/// anything that isn't a socket is left alone.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another daemon is already listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if !parent.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// The user id of the process on the other end of the socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the fd is open for as long as `stream` is borrowed. `cred` is a valid, writable
+    // `ucred` and `len` holds its exact size, so the kernel writes at most that many bytes.
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// The user id of the process on the other end of the socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    // SAFETY: the fd is open for as long as `stream` is borrowed, and `uid` and `gid` are valid,
+    // writable locals of the types `getpeereid` writes.
+    let res = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
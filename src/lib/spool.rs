@@ -0,0 +1,114 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::output::QueuedEntry;
+
+/// A write-ahead copy of the output queue, so entries that haven't been written to the log yet
+/// survive the daemon crashing.
+///
+/// Every queued entry is appended as a JSON line before it's pushed, and once a flush has written
+/// entries to the log the spool is rewritten with only what's still waiting. Entries are written
+/// at least once: a crash between writing the log and rewriting the spool logs them again.
+#[derive(Debug)]
+pub struct Spool {
+    pub path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    /// Opens the spool at `path`, returning it with the entries left in it by the last run.
+    /// A line that can't be read, like one cut short by a crash, is skipped, and the spool is
+    /// rewritten with what could be read so new entries don't end up on the end of it.
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<QueuedEntry>)> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut entries = vec![];
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).split(b'\n') {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                match serde_json::from_slice::<QueuedEntry>(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => eprintln!("Skipping unreadable entry in {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut spool = Self {
+            path: path.to_path_buf(),
+            file,
+        };
+        spool.rewrite(&entries)?;
+        Ok((spool, entries))
+    }
+
+    /// Appends an entry and waits for it to reach the disk.
+    pub fn append(&mut self, entry: &QueuedEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /// Replaces what's in the spool with `entries`.
+    pub fn rewrite(&mut self, entries: &[QueuedEntry]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("spool.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for entry in entries {
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+            tmp.write_all(line.as_bytes())?;
+        }
+        tmp.sync_data()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{output::OutputEntry, scratch::ScratchDir};
+
+    fn message(entry: &QueuedEntry) -> String {
+        match &entry.1 {
+            OutputEntry::Message(message) => message.clone(),
+            other => panic!("unexpected entry {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replays_a_spool_cut_short_by_a_crash() {
+        let dir = ScratchDir::new("spool");
+        let path = dir.join("output.spool");
+
+        let (mut spool, entries) = Spool::open(&path).unwrap();
+        assert!(entries.is_empty());
+        spool.append(&(1, OutputEntry::Message("first".to_string()))).unwrap();
+        spool.append(&(2, OutputEntry::Message("second".to_string()))).unwrap();
+        drop(spool);
+
+        // A crash while appending leaves half a line, without its newline.
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &content[..content.len() - 10]).unwrap();
+
+        let (mut spool, entries) = Spool::open(&path).unwrap();
+        assert_eq!(entries.iter().map(message).collect::<Vec<_>>(), ["first"]);
+        spool.append(&(3, OutputEntry::Message("third".to_string()))).unwrap();
+        drop(spool);
+
+        let (_, entries) = Spool::open(&path).unwrap();
+        assert_eq!(entries.iter().map(message).collect::<Vec<_>>(), ["first", "third"]);
+        assert_eq!(entries[1].0, 3);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::StageRecord;
+
+/// How a stage run ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StageOutcome {
+    Success,
+    /// The stage exited unsuccessfully, with its exit code if it had one.
+    Failed(Option<i32>),
+    /// The stage's process was killed because the system was shut down.
+    Killed,
+    /// The stage ran past its timeout and its process group was killed.
+    TimedOut,
+    /// The stage's process couldn't be started.
+    SpawnFailed(String),
+}
+
+impl Display for StageOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StageOutcome::Success => write!(f, "ok"),
+            StageOutcome::Failed(Some(code)) => write!(f, "FAILED (exit {})", code),
+            StageOutcome::Failed(None) => write!(f, "FAILED"),
+            StageOutcome::Killed => write!(f, "KILLED"),
+            StageOutcome::TimedOut => write!(f, "TIMED OUT"),
+            StageOutcome::SpawnFailed(e) => write!(f, "FAILED TO START ({})", e),
+        }
+    }
+}
+
+/// The last run of a single stage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageState {
+    /// The command that ran, so an edited stage isn't treated as the old one.
+    pub command: String,
+    /// Unix seconds.
+    pub last_run: u64,
+    pub outcome: StageOutcome,
+}
+
+impl From<&StageRecord> for StageState {
+    fn from(record: &StageRecord) -> Self {
+        Self {
+            command: record.command.clone(),
+            last_run: (record.started_at / 1000) as u64,
+            outcome: record.outcome.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemState {
+    pub stages: BTreeMap<u8, StageState>,
+}
+
+/// Per-system scheduling state, kept in memory and persisted to one file per system.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    pub dir: PathBuf,
+    systems: Arc<RwLock<BTreeMap<String, SystemState>>>,
+}
+
+impl StateStore {
+    pub fn new(dir: PathBuf) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            eprintln!("Failed to create state directory: {}", e);
+        }
+
+        Self {
+            dir,
+            systems: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    fn file_path(&self, system: &str) -> PathBuf {
+        self.dir.join(format!("{}.state", system))
+    }
+
+    /// Returns the state of a system, reading it from disk the first time it's asked for.
+    pub fn load(&self, system: &str) -> SystemState {
+        if let Some(state) = self.systems.read().unwrap().get(system) {
+            return state.clone();
+        }
+
+        let state = match std::fs::read(self.file_path(system)) {
+            Ok(bytes) => bincode::deserialize(&bytes).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable state for system '{}': {}", system, e);
+                SystemState::default()
+            }),
+            Err(_) => SystemState::default(),
+        };
+
+        self.systems
+            .write()
+            .unwrap()
+            .insert(system.to_string(), state.clone());
+        state
+    }
+
+    /// Records a stage run and writes the system's state back to disk.
+    pub fn record(&self, system: &str, stage_id: u8, stage_state: StageState) {
+        let state = {
+            let mut systems = self.systems.write().unwrap();
+            let state = systems.entry(system.to_string()).or_default();
+            state.stages.insert(stage_id, stage_state);
+            state.clone()
+        };
+
+        if let Err(e) = self.save(system, &state) {
+            eprintln!("Failed to save state for system '{}': {}", system, e);
+        }
+    }
+
+    /// Writes to a temporary file first so a crash mid-write can't corrupt the state.
+    fn save(&self, system: &str, state: &SystemState) -> Result<(), String> {
+        let bytes = bincode::serialize(state).map_err(|e| e.to_string())?;
+        let path = self.file_path(system);
+        let tmp_path = path.with_extension("state.tmp");
+
+        let mut file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+        file.write_all(&bytes).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp_path, &path).map_err(|e| e.to_string())
+    }
+}
@@ -1,19 +1,195 @@
 use std::{
     collections::BTreeMap,
-    sync::{Arc, RwLock},
-    time::{Duration, UNIX_EPOCH},
+    os::unix::process::ExitStatusExt,
+    process::Command,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use priority::queue::{prelude::Prio, Queue};
+use serde::Deserialize;
 
-use super::system_stage::SStage;
+use crate::{
+    cancel::CancelToken,
+    duration::format_duration,
+    environment::Environment,
+    info::{LastRun, NextDue, ScheduleInfo, StageInfo, SystemInfo},
+    output::{OutputEntry, OutputQueue, PipelineRecord, StageRecord},
+    process::run_process,
+    state::{StageOutcome, StageState, StateStore, SystemState},
+    template::expand_runtime,
+};
+
+use super::system_stage::{RetryPolicy, SStage, Schedule};
+
+/// The most missed runs `CatchUp::RunAll` will replay for a single stage.
+const MAX_CATCH_UP_RUNS: u64 = 100;
+
+/// What a pipeline does when one of its stages fails.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnFailure {
+    /// Stop the pipeline at the failed stage.
+    Abort,
+    /// Carry on with the next stage regardless.
+    Continue,
+    /// Run a cleanup stage, then stop the pipeline.
+    Cleanup(SStage),
+}
+
+/// Runs a system's stages in order, one after the other, on a single schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub schedule: Schedule,
+    pub on_failure: OnFailure,
+}
+
+/// The stage id a pipeline's state is kept under, since its own stages start at 1.
+const PIPELINE_ID: u8 = 0;
+
+/// What to do with runs that were missed while the daemon wasn't running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CatchUp {
+    /// Run a stage once if any of its runs were missed.
+    #[default]
+    RunOnce,
+    /// Drop missed runs and wait for the next scheduled one.
+    Skip,
+    /// Run a stage once for every run that was missed.
+    RunAll,
+}
+
+impl FromStr for CatchUp {
+    type Err = String;
 
-#[derive(Debug, Clone)]
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "run_once" | "run-once" => Ok(CatchUp::RunOnce),
+            "skip" => Ok(CatchUp::Skip),
+            "run_all" | "run-all" => Ok(CatchUp::RunAll),
+            _ => Err(format!(
+                "invalid catch_up '{}'; expected run_once, skip or run_all",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for CatchUp {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Lets the daemon stop a running system.
+///
+/// Stopping is cooperative: the system finishes the stage it's running and then returns
+/// from `SystemBuilder::start`. Killing additionally kills the stage's process.
+#[derive(Debug, Clone, Default)]
+pub struct SystemHandle {
+    stop: CancelToken,
+    kill: CancelToken,
+    started: CancelToken,
+    finished: CancelToken,
+    /// The handle of the system's old definition, which has to finish before this one starts.
+    previous: Arc<Mutex<Option<SystemHandle>>>,
+    /// What the system's loop will run next and when, kept up to date as it goes.
+    next_due: Arc<Mutex<Option<NextDue>>>,
+}
+
+impl SystemHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle for a system taking over from `previous`, like after a reload changed it.
+    /// The system waits for `previous` to finish before it starts.
+    pub fn after(previous: SystemHandle) -> Self {
+        let handle = Self::new();
+        handle.previous.lock().unwrap().replace(previous);
+        handle
+    }
+
+    pub fn stop(&self) {
+        self.stop.cancel();
+        if let Some(previous) = self.previous.lock().unwrap().as_ref() {
+            previous.stop();
+        }
+    }
+
+    /// Stops the system and kills the process of the stage it's running, if any.
+    pub fn kill(&self) {
+        self.stop.cancel();
+        self.kill.cancel();
+        if let Some(previous) = self.previous.lock().unwrap().as_ref() {
+            previous.kill();
+        }
+    }
+
+    /// Waits for the system this one takes over from, if any, to finish.
+    fn wait_for_previous(&self) {
+        let previous = self.previous.lock().unwrap().clone();
+        if let Some(previous) = previous {
+            previous.finished.wait();
+            self.previous.lock().unwrap().take();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    /// Whether `SystemBuilder::start` has been called, rather than waiting for a free thread.
+    pub fn is_started(&self) -> bool {
+        self.started.is_cancelled()
+    }
+
+    /// Whether `SystemBuilder::start` has returned.
+    pub fn is_finished(&self) -> bool {
+        self.finished.is_cancelled()
+    }
+
+    /// Waits up to `timeout` for the system to finish. Returns whether it did.
+    pub fn wait_finished(&self, timeout: Duration) -> bool {
+        self.finished.wait_timeout(timeout)
+    }
+
+    /// When the system's loop next wakes up to run something, including waits before a retry.
+    /// `None` until the system has started, or if nothing is scheduled.
+    pub fn next_due(&self) -> Option<NextDue> {
+        self.next_due.lock().unwrap().clone()
+    }
+
+    fn set_next_due(&self, next_due: Option<NextDue>) {
+        *self.next_due.lock().unwrap() = next_due;
+    }
+}
+
+/// Marks a system as finished when dropped, even if the system panicked.
+struct FinishGuard<'a>(&'a SystemHandle);
+
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        self.0.finished.cancel();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SystemBuilder {
     pub name: String,
     pub description: String,
     pub stages: BTreeMap<u8, SStage>,
     pub shell: String,
+    pub catch_up: CatchUp,
+    /// How long any stage may run before it's killed, unless the stage sets its own.
+    pub timeout: Option<Duration>,
+    /// How failed stages are retried, unless the stage sets its own policy.
+    pub retry: RetryPolicy,
+    /// When set, the stages run as one pipeline instead of on their own schedules.
+    pub pipeline: Option<Pipeline>,
+    pub env: Environment,
 }
 
 impl SystemBuilder {
@@ -23,6 +199,11 @@ impl SystemBuilder {
             description: desc,
             stages: BTreeMap::new(),
             shell,
+            catch_up: CatchUp::default(),
+            timeout: None,
+            retry: RetryPolicy::default(),
+            pipeline: None,
+            env: Environment::default(),
         }
     }
 
@@ -31,54 +212,456 @@ impl SystemBuilder {
         self.stages.insert(stage_id, stage);
     }
 
+    pub fn set_catch_up(&mut self, catch_up: CatchUp) {
+        self.catch_up = catch_up;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn set_pipeline(&mut self, pipeline: Option<Pipeline>) {
+        self.pipeline = pipeline;
+    }
+
+    pub fn set_env(&mut self, env: Environment) {
+        self.env = env;
+    }
+
     pub fn get_stages(&self) -> Vec<&SStage> {
         self.stages.iter().map(|(_, v)| v).collect::<Vec<&SStage>>()
     }
 
-    pub fn start(self, output_queue: Arc<RwLock<Queue<String>>>) {
-        let mut last_ran_stages: BTreeMap<u8, u64> = BTreeMap::new();
+    /// The things that get scheduled: each stage, or the pipeline as a whole.
+    /// Each is an id, the command its state is matched against, and its schedule.
+    fn scheduled(&self) -> Vec<(u8, String, &Schedule)> {
+        match &self.pipeline {
+            Some(pipeline) => {
+                let commands = self
+                    .stages
+                    .values()
+                    .map(|stage| stage.command.as_str())
+                    .collect::<Vec<&str>>();
+                vec![(PIPELINE_ID, commands.join("\n"), &pipeline.schedule)]
+            }
+            None => self
+                .stages
+                .iter()
+                .map(|(stage_id, stage)| (*stage_id, stage.command.clone(), &stage.schedule))
+                .collect(),
+        }
+    }
 
-        loop {
-            let current_time_u64 = UNIX_EPOCH.elapsed().unwrap().as_secs();
+    /// Describes the system for `toaster list` and `toaster describe`, using its persisted
+    /// state for the last and next runs. Stopped systems have no next run.
+    pub fn info(&self, state: &SystemState, running: bool) -> SystemInfo {
+        let now = chrono::Local::now();
+        let mut schedules: BTreeMap<u8, ScheduleInfo> = BTreeMap::new();
+
+        for (id, command, schedule) in self.scheduled() {
+            let previous = state
+                .stages
+                .get(&id)
+                .filter(|previous| previous.command == command);
+            let next_run = match (previous, schedule) {
+                _ if !running => None,
+                (Some(previous), _) => Some(schedule.next_run(previous.last_run)),
+                (None, Schedule::Cron(_)) => Some(schedule.next_run(now.timestamp() as u64)),
+                (None, Schedule::Interval(_)) => Some(now.timestamp() as u64),
+            };
+
+            schedules.insert(
+                id,
+                ScheduleInfo {
+                    schedule: schedule.to_string(),
+                    next_run: next_run.filter(|next_run| *next_run != u64::MAX),
+                    last_run: previous.map(|previous| LastRun {
+                        at: previous.last_run,
+                        outcome: previous.outcome.to_string(),
+                    }),
+                },
+            );
+        }
+
+        SystemInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            shell: self.shell.clone(),
+            running,
+            mode: match self.pipeline {
+                Some(_) => "pipeline".to_string(),
+                None => "independent".to_string(),
+            },
+            pipeline: schedules.remove(&PIPELINE_ID),
+            stages: self
+                .stages
+                .iter()
+                .map(|(id, stage)| StageInfo {
+                    id: *id,
+                    command: stage.command.clone(),
+                    schedule: schedules.remove(id),
+                })
+                .collect(),
+        }
+    }
+
+    /// A message about this system for the output queue.
+    fn message(&self, message: String) -> OutputEntry {
+        OutputEntry::SystemMessage {
+            system: self.name.clone(),
+            message,
+        }
+    }
+
+    /// Runs whatever is scheduled under `id`.
+    fn run_scheduled(
+        &self,
+        id: u8,
+        output_queue: &OutputQueue,
+        state: &StateStore,
+        handle: &SystemHandle,
+    ) {
+        match (&self.pipeline, self.stages.get(&id)) {
+            (Some(pipeline), _) => self.run_pipeline(pipeline, output_queue, state, handle),
+            (None, Some(stage)) => self.run_stage(id, stage, output_queue, state, handle),
+            (None, None) => {}
+        }
+    }
 
-            for (stage_id, stage) in &self.stages {
-                let execution_schedule = stage.schedule.clone();
-                let scheduled_time = execution_schedule.get_as_u64();
+    /// Works out when each stage last ran from the persisted state, applying the catch-up policy.
+    /// Stages the policy wants to replay are run before this returns.
+    fn restore_last_runs(
+        &self,
+        output_queue: &OutputQueue,
+        state: &StateStore,
+        handle: &SystemHandle,
+    ) -> BTreeMap<u8, u64> {
+        let persisted = state.load(&self.name);
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let mut last_ran_stages: BTreeMap<u8, u64> = BTreeMap::new();
 
-                if last_ran_stages.get(stage_id).unwrap_or(&0) + scheduled_time <= current_time_u64
-                {
-                    let command = stage.command.clone();
+        for (id, command, schedule) in self.scheduled() {
+            let previous = persisted
+                .stages
+                .get(&id)
+                .filter(|previous| previous.command == command);
 
-                    let output_res = std::process::Command::new(self.shell.as_str())
-                        .arg("-c")
-                        .arg(command.as_str())
-                        .output();
+            let Some(previous) = previous else {
+                // Never ran: cron stages wait for their next match, intervals run straight away.
+                if let Schedule::Cron(_) = schedule {
+                    last_ran_stages.insert(id, now);
+                }
+                continue;
+            };
+
+            if schedule.next_run(previous.last_run) > now {
+                last_ran_stages.insert(id, previous.last_run);
+                continue;
+            }
 
-                    let output = match output_res {
-                        Ok(output) => output,
-                        Err(e) => {
-                            eprintln!("Failed to run command: {}", e);
-                            continue;
+            match self.catch_up {
+                // Still due, so the scheduler picks it up on its first pass.
+                CatchUp::RunOnce => {
+                    last_ran_stages.insert(id, previous.last_run);
+                }
+                CatchUp::Skip => {
+                    let aligned = match schedule.get_as_u64() {
+                        Some(interval) if interval > 0 => {
+                            previous.last_run + (now - previous.last_run) / interval * interval
                         }
+                        _ => now,
                     };
+                    last_ran_stages.insert(id, aligned);
+                }
+                CatchUp::RunAll => {
+                    let mut missed = 0;
+                    let mut due = schedule.next_run(previous.last_run);
+                    while due <= now && missed < MAX_CATCH_UP_RUNS {
+                        missed += 1;
+                        due = schedule.next_run(due);
+                    }
 
-                    let mut final_output = String::new();
-                    final_output.push_str(
-                        format!(
-                            "{}: Output: {}",
-                            self.name.clone(),
-                            String::from_utf8(output.stdout).unwrap()
-                        )
-                        .as_str(),
-                    );
+                    let what = match id {
+                        PIPELINE_ID => "the pipeline".to_string(),
+                        id => format!("stage {}", id),
+                    };
+                    output_queue.push(self.message(format!(
+                        "Catching up on {} missed run(s) of {}",
+                        missed, what
+                    )));
+                    for run in 1..=missed {
+                        if handle.is_stopped() {
+                            break;
+                        }
+                        handle.set_next_due(Some(NextDue {
+                            what: format!("catch-up run {} of {} of {}", run, missed, what),
+                            at: UNIX_EPOCH.elapsed().unwrap().as_secs(),
+                        }));
+                        self.run_scheduled(id, output_queue, state, handle);
+                    }
+                    last_ran_stages.insert(id, UNIX_EPOCH.elapsed().unwrap().as_secs());
+                }
+            }
+        }
 
-                    output_queue.write().unwrap().push(Prio::wlip(final_output));
+        last_ran_stages
+    }
 
-                    last_ran_stages.insert(*stage_id, current_time_u64);
+    /// Runs a single stage's process and describes how it went.
+    fn execute_stage(
+        &self,
+        stage_id: u8,
+        stage: &SStage,
+        handle: &SystemHandle,
+        attempt: u32,
+    ) -> StageRecord {
+        let now = chrono::Local::now();
+        let started_at = now.timestamp_millis();
+        let started = Instant::now();
+        let timeout = stage.timeout.or(self.timeout);
+        let (command, output_res) = match expand_runtime(&stage.command, now) {
+            Ok(command) => {
+                let mut process = Command::new(self.shell.as_str());
+                process.arg("-c").arg(command.as_str());
+                self.env.apply(&mut process);
+                let output_res = run_process(process, &handle.kill, timeout);
+                (command, output_res.map_err(|e| e.to_string()))
+            }
+            // Running the command as it's written would hand the shell `$${` escapes.
+            Err(e) => (
+                stage.command.clone(),
+                Err(format!("couldn't expand the command: {}", e)),
+            ),
+        };
+
+        let mut record = StageRecord {
+            system: self.name.clone(),
+            stage: stage_id,
+            command,
+            started_at,
+            duration_ms: 0,
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: None,
+            signal: None,
+            outcome: StageOutcome::Success,
+            attempt,
+            max_attempts: stage.retry.as_ref().unwrap_or(&self.retry).retries + 1,
+        };
+
+        match output_res {
+            Ok(output) => {
+                record.stdout = String::from_utf8_lossy(&output.stdout).to_string();
+                record.stderr = String::from_utf8_lossy(&output.stderr).to_string();
+                record.exit_code = output.status.code();
+                record.signal = output.status.signal();
+                record.outcome = if output.timed_out {
+                    StageOutcome::TimedOut
+                } else if output.killed {
+                    StageOutcome::Killed
+                } else if output.status.success() {
+                    StageOutcome::Success
+                } else {
+                    StageOutcome::Failed(output.status.code())
+                };
+            }
+            Err(e) => {
+                eprintln!("Failed to run command: {}", e);
+                record.outcome = StageOutcome::SpawnFailed(e);
+            }
+        }
+
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        record
+    }
+
+    /// Runs a stage, retrying it as its retry policy allows.
+    /// Each attempt's record, and a note before each retry, is handed to `report`.
+    /// Returns the record of the last attempt.
+    fn run_attempts(
+        &self,
+        stage_id: u8,
+        stage: &SStage,
+        handle: &SystemHandle,
+        report: &mut dyn FnMut(OutputEntry),
+    ) -> StageRecord {
+        let retry = stage.retry.as_ref().unwrap_or(&self.retry);
+        let mut attempt = 1;
+
+        loop {
+            let record = self.execute_stage(stage_id, stage, handle, attempt);
+            let retryable = matches!(
+                record.outcome,
+                StageOutcome::Failed(_) | StageOutcome::TimedOut | StageOutcome::SpawnFailed(_)
+            );
+            report(OutputEntry::Stage(record.clone()));
+
+            if !retryable || attempt > retry.retries || handle.is_stopped() {
+                return record;
+            }
+
+            let delay = retry.delay_before(attempt);
+            report(self.message(format!(
+                "Retrying stage {} in {} (retry {} of {})",
+                stage_id,
+                format_duration(delay),
+                attempt,
+                retry.retries
+            )));
+            handle.set_next_due(Some(NextDue {
+                what: format!("retry {} of {} of stage {}", attempt, retry.retries, stage_id),
+                at: UNIX_EPOCH.elapsed().unwrap().as_secs() + delay.as_secs(),
+            }));
+            if handle.stop.wait_timeout(delay) {
+                return record;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Runs a single stage on its own, queueing and remembering every attempt as it finishes.
+    fn run_stage(
+        &self,
+        stage_id: u8,
+        stage: &SStage,
+        output_queue: &OutputQueue,
+        state: &StateStore,
+        handle: &SystemHandle,
+    ) {
+        self.run_attempts(stage_id, stage, handle, &mut |entry| {
+            if let OutputEntry::Stage(record) = &entry {
+                state.record(&self.name, stage_id, stage_state(stage, record));
+            }
+            output_queue.push(entry);
+        });
+    }
+
+    /// Runs every stage in order, handling a failure as the pipeline says.
+    /// The whole run is queued as a single entry once it's over.
+    fn run_pipeline(
+        &self,
+        pipeline: &Pipeline,
+        output_queue: &OutputQueue,
+        state: &StateStore,
+        handle: &SystemHandle,
+    ) {
+        let started_at = chrono::Local::now().timestamp_millis();
+        let started = Instant::now();
+        let mut record = PipelineRecord {
+            system: self.name.clone(),
+            started_at,
+            duration_ms: 0,
+            stages: vec![],
+            failed_stage: None,
+            cleanup: None,
+        };
+        let mut collect = |entry: OutputEntry| {
+            if let OutputEntry::Stage(stage_record) = entry {
+                record.stages.push(stage_record);
+            }
+        };
+
+        let mut failed_stage = None;
+        for (stage_id, stage) in &self.stages {
+            if handle.is_stopped() {
+                break;
+            }
+
+            let last = self.run_attempts(*stage_id, stage, handle, &mut collect);
+            state.record(&self.name, *stage_id, stage_state(stage, &last));
+            if last.is_failure() {
+                failed_stage.get_or_insert(*stage_id);
+                if pipeline.on_failure != OnFailure::Continue {
+                    break;
+                }
+            }
+        }
+        record.failed_stage = failed_stage;
+
+        if let (Some(_), OnFailure::Cleanup(cleanup)) = (failed_stage, &pipeline.on_failure) {
+            let cleanup_id = self.stages.len() as u8 + 1;
+            let mut attempts = vec![];
+            self.run_attempts(cleanup_id, cleanup, handle, &mut |entry| {
+                if let OutputEntry::Stage(stage_record) = entry {
+                    attempts.push(stage_record);
+                }
+            });
+            record.cleanup = attempts.pop();
+        }
+
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        let (_, command, _) = self.scheduled().remove(0);
+        state.record(
+            &self.name,
+            PIPELINE_ID,
+            StageState {
+                command,
+                last_run: (started_at / 1000) as u64,
+                outcome: record.outcome(),
+            },
+        );
+        output_queue.push(OutputEntry::Pipeline(record));
+    }
+
+    /// Whichever stage, or the pipeline, the loop will run first given when each last ran.
+    fn next_due(&self, last_ran_stages: &BTreeMap<u8, u64>) -> Option<NextDue> {
+        self.scheduled()
+            .into_iter()
+            .map(|(id, _, schedule)| NextDue {
+                what: match id {
+                    PIPELINE_ID => "pipeline".to_string(),
+                    id => format!("stage {}", id),
+                },
+                at: schedule.next_run(*last_ran_stages.get(&id).unwrap_or(&0)),
+            })
+            .filter(|due| due.at != u64::MAX)
+            .min_by_key(|due| due.at)
+    }
+
+    /// Runs the system's schedule until `handle` is stopped.
+    pub fn start(self, output_queue: OutputQueue, state: StateStore, handle: SystemHandle) {
+        let _finished = FinishGuard(&handle);
+        // The old definition may still be running its last stage, and both would share its state.
+        handle.wait_for_previous();
+        handle.started.cancel();
+        let mut last_ran_stages = self.restore_last_runs(&output_queue, &state, &handle);
+        handle.set_next_due(self.next_due(&last_ran_stages));
+
+        while !handle.is_stopped() {
+            let current_time_u64 = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+            for (id, _, schedule) in self.scheduled() {
+                if handle.is_stopped() {
+                    break;
                 }
 
-                std::thread::sleep(Duration::from_millis(100));
+                let last_ran = *last_ran_stages.get(&id).unwrap_or(&0);
+
+                if schedule.next_run(last_ran) <= current_time_u64 {
+                    self.run_scheduled(id, &output_queue, &state, &handle);
+                    last_ran_stages.insert(id, current_time_u64);
+                }
+                handle.set_next_due(self.next_due(&last_ran_stages));
+
+                if handle.stop.wait_timeout(Duration::from_millis(100)) {
+                    break;
+                }
             }
         }
+
+        println!("System '{}' stopped.", self.name);
+    }
+}
+
+/// The state to remember for a stage's run. It keeps the command as configured rather than
+/// as it ran, so values that change between runs like `${date}` don't look like an edit.
+fn stage_state(stage: &SStage, record: &StageRecord) -> StageState {
+    StageState {
+        command: stage.command.clone(),
+        ..StageState::from(record)
     }
 }
@@ -1,5 +1,14 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
 
-#[derive(Debug, Clone)]
+use chrono::{Local, TimeZone};
+
+use crate::cron::CronExpr;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Time {
     pub month: u8,
     pub day: u8,
@@ -20,123 +29,185 @@ impl Time {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Schedule(Time);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Runs every `MM:DD:HH:MM:SS` since the last run.
+    Interval(Time),
+    /// Runs whenever local time matches a cron expression.
+    Cron(CronExpr),
+}
 
 impl Schedule {
     pub fn new(time: Time) -> Self {
-        Self(time)
+        Self::Interval(time)
     }
 
+    /// Parses either an interval (`MM:DD:HH:MM:SS`) or a cron expression (`0 9 * * mon-fri`).
     pub fn from_str(s: &str) -> Result<Self, String> {
-        // format: MM::DD::HH::MM::SS
-        let mut time = Time::new(0, 0, 0, 0, 0);
-        let mut split = s.split(':');
-
-        if let Some(month) = split.next() {
-            time.month = month.parse::<u8>().unwrap();
-        } else {
-            return Err("Invalid schedule format".to_string());
+        let s = s.trim();
+        if s.starts_with('@') || s.contains(char::is_whitespace) {
+            return s.parse::<CronExpr>().map(Self::Cron);
         }
 
-        if let Some(day) = split.next() {
-            time.day = day.parse::<u8>().unwrap();
-        } else {
-            return Err("Invalid schedule format".to_string());
+        // format: MM::DD::HH::MM::SS
+        let fields = s.split(':').collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Invalid schedule format '{}'; expected MM:DD:HH:MM:SS or a cron expression",
+                s
+            ));
         }
 
-        if let Some(hour) = split.next() {
-            time.hour = hour.parse::<u8>().unwrap();
-        } else {
-            return Err("Invalid schedule format".to_string());
+        let mut values = [0u8; 5];
+        for (value, field) in values.iter_mut().zip(fields) {
+            *value = field
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid schedule format '{}'; '{}' isn't a number", s, field))?;
         }
 
-        if let Some(minute) = split.next() {
-            time.minute = minute.parse::<u8>().unwrap();
-        } else {
-            return Err("Invalid schedule format".to_string());
-        }
+        let [month, day, hour, minute, second] = values;
+        Ok(Self::Interval(Time::new(month, day, hour, minute, second)))
+    }
 
-        if let Some(second) = split.next() {
-            time.second = second.parse::<u8>().unwrap();
-        } else {
-            return Err("Invalid schedule format".to_string());
-        }
+    /// The interval in seconds, or `None` for cron schedules.
+    /// A month is counted as 30 days.
+    pub fn get_as_u64(&self) -> Option<u64> {
+        let Self::Interval(time) = self else {
+            return None;
+        };
+
+        let mut secs = 0;
+        secs += time.month as u64 * 60 * 60 * 24 * 30;
+        secs += time.day as u64 * 60 * 60 * 24;
+        secs += time.hour as u64 * 60 * 60;
+        secs += time.minute as u64 * 60;
+        secs += time.second as u64;
+        Some(secs)
+    }
 
-        Ok(Self(time))
+    /// When the stage should next run, in unix seconds, given when it last ran.
+    /// Returns `u64::MAX` for a cron expression that never matches.
+    pub fn next_run(&self, last_ran: u64) -> u64 {
+        match self {
+            Self::Interval(_) => last_ran + self.get_as_u64().unwrap_or_default(),
+            Self::Cron(cron) => Local
+                .timestamp_opt(last_ran as i64, 0)
+                .single()
+                .and_then(|last_ran| cron.next_after(last_ran))
+                .map(|next| next.timestamp() as u64)
+                .unwrap_or(u64::MAX),
+        }
     }
 
-    pub fn get_as_u64(&self) -> u64 {
-        let mut time = 0;
-        time += self.0.month as u64 * 60 * 60 * 24 * 30;
-        time += self.0.day as u64 * 60 * 60 * 24;
-        time += self.0.hour as u64 * 60 * 60;
-        time += self.0.minute as u64 * 60;
-        time += self.0.second as u64;
-        time
+    fn interval(&self) -> Option<&Time> {
+        match self {
+            Self::Interval(time) => Some(time),
+            Self::Cron(_) => None,
+        }
     }
 
     /// Checks if the schedule is only for seconds
     /// MM::DD::HH::MM are all 0's if this case is true
     pub fn is_sec_only(&self) -> bool {
-        if self.0.month == 0 && self.0.day == 0 && self.0.hour == 0 && self.0.minute == 0 {
-            true
-        } else {
-            false
-        }
+        self.interval()
+            .is_some_and(|t| t.month == 0 && t.day == 0 && t.hour == 0 && t.minute == 0)
     }
 
     /// Checks if the schedule is only for minutes
     /// MM::DD::HH::MM are all 0's if this case is true
     pub fn is_min_only(&self) -> bool {
-        if self.0.month == 0 && self.0.day == 0 && self.0.hour == 0 {
-            true
-        } else {
-            false
-        }
+        self.interval()
+            .is_some_and(|t| t.month == 0 && t.day == 0 && t.hour == 0)
     }
 
     /// Checks if the schedule is only for hours
     /// MM::DD::HH::MM are all 0's if this case is true
     pub fn is_hour_only(&self) -> bool {
-        if self.0.month == 0 && self.0.day == 0 {
-            true
-        } else {
-            false
-        }
+        self.interval().is_some_and(|t| t.month == 0 && t.day == 0)
     }
 
     /// Checks if the schedule is only for days
     /// MM::DD::HH::MM are all 0's if this case is true
     pub fn is_day_only(&self) -> bool {
-        if self.0.month == 0 {
-            true
-        } else {
-            false
-        }
+        self.interval().is_some_and(|t| t.month == 0)
     }
 
     /// Checks if the schedule is only for months
     /// MM::DD::HH::MM are all 0's if this case is true
     pub fn is_month_only(&self) -> bool {
-        if self.0.day == 0 {
-            true
-        } else {
-            false
+        self.interval().is_some_and(|t| t.day == 0)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Interval(t) => write!(
+                f,
+                "{:02}:{:02}:{:02}:{:02}:{:02}",
+                t.month, t.day, t.hour, t.minute, t.second
+            ),
+            Self::Cron(cron) => write!(f, "{}", cron.source),
+        }
+    }
+}
+
+/// How a failed stage is retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times to retry after the first attempt fails.
+    pub retries: u32,
+    /// How long to wait before the first retry.
+    pub delay: Duration,
+    /// What the delay is multiplied by after each retry; `1.0` keeps it fixed.
+    pub backoff: f64,
+    /// The longest the delay may grow to.
+    pub max_delay: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::from_secs(1),
+            backoff: 1.0,
+            max_delay: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the given retry, counting from 1.
+    pub fn delay_before(&self, retry: u32) -> Duration {
+        let factor = self.backoff.max(1.0).powi(retry.saturating_sub(1) as i32);
+        let delay = Duration::try_from_secs_f64(self.delay.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX);
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SStage {
     /// The command to be ran on the schedule
     pub command: String,
     /// When does this system run?
     pub schedule: Schedule,
+    /// How long the stage may run before it's killed, overriding the system's timeout.
+    pub timeout: Option<Duration>,
+    /// How the stage is retried when it fails, overriding the system's policy.
+    pub retry: Option<RetryPolicy>,
 }
 
 impl SStage {
     pub fn new(command: String, schedule: Schedule) -> Self {
-        Self { command, schedule }
+        Self {
+            command,
+            schedule,
+            timeout: None,
+            retry: None,
+        }
     }
 }
\ No newline at end of file
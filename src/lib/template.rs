@@ -0,0 +1,197 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local,
+};
+
+/// Replaces every `${name}` in `text` with what `lookup` returns for `name`.
+///
+/// `$${` is an escaped `${`. It's turned into `${` when `unescape` is set and kept as it is
+/// otherwise, so text can be expanded in several passes.
+/// Fails with every name `lookup` didn't know, or if a `${` is never closed.
+pub fn expand(
+    text: &str,
+    unescape: bool,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut undefined = Vec::new();
+    let mut rest = text;
+
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+
+        if rest.starts_with("$${") {
+            out.push_str(if unescape { "${" } else { "$${" });
+            rest = &rest[3..];
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| "unterminated `${`".to_string())?;
+            let name = reference[..end].trim();
+            match lookup(name) {
+                Some(value) => out.push_str(&value),
+                None => undefined.push(name.to_string()),
+            }
+            rest = &reference[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+
+    if !undefined.is_empty() {
+        return Err(format!("undefined variable(s): {}", undefined.join(", ")));
+    }
+    Ok(out)
+}
+
+/// Escapes every `${` in a value substituted into a command, so a later pass leaves it as it is.
+pub fn escape(value: &str) -> String {
+    value.replace("${", "$${")
+}
+
+/// Whether `name` is a built-in that can only be resolved when a stage runs, like `date`.
+pub fn is_runtime(name: &str) -> bool {
+    name == "date" || name.starts_with("date:")
+}
+
+/// Checks a runtime built-in at config time, returning it as a reference to expand later.
+pub fn defer_runtime(name: &str) -> Option<String> {
+    if let Some(format) = name.strip_prefix("date:") {
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return None;
+        }
+    }
+    Some(format!("${{{}}}", name))
+}
+
+/// The value of a runtime built-in.
+/// `${date}` is the date a stage runs on, and `${date:FORMAT}` formats it with strftime.
+pub fn runtime_value(name: &str, now: DateTime<Local>) -> Option<String> {
+    match name {
+        "date" => Some(now.format("%Y-%m-%d").to_string()),
+        _ => {
+            let format = name.strip_prefix("date:")?;
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return None;
+            }
+            Some(now.format(format).to_string())
+        }
+    }
+}
+
+/// Expands the runtime built-ins left in a stage's command, and unescapes `$${`.
+/// Anything else was resolved when the config was parsed.
+///
+/// `${args.name}` becomes a reference to the variable `arg_env_var` names, quoted to suit
+/// where it is in the command, so the argument's value reaches the stage as one word and is
+/// never read as shell code.
+pub fn expand_runtime(command: &str, now: DateTime<Local>) -> Result<String, String> {
+    expand(&quote_arg_references(command), true, |name| runtime_value(name, now))
+}
+
+/// Rewrites each `${args.name}` in a shell command to an escaped `$${TOASTER_ARG_NAME}`,
+/// wrapped in whatever quotes keep its value a single word in that spot.
+fn quote_arg_references(command: &str) -> String {
+    #[derive(PartialEq)]
+    enum Quote {
+        None,
+        Single,
+        Double,
+    }
+
+    let mut out = String::with_capacity(command.len());
+    let mut quote = Quote::None;
+    let mut rest = command;
+
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("$${") {
+            out.push_str("$${");
+            rest = &rest[3..];
+            continue;
+        }
+        if let Some(reference) = rest.strip_prefix("${args.") {
+            if let Some(end) = reference.find('}') {
+                let var = arg_env_var(reference[..end].trim());
+                match quote {
+                    Quote::None => out.push_str(&format!("\"$${{{}}}\"", var)),
+                    Quote::Double => out.push_str(&format!("$${{{}}}", var)),
+                    Quote::Single => out.push_str(&format!("'\"$${{{}}}\"'", var)),
+                }
+                rest = &reference[end + 1..];
+                continue;
+            }
+        }
+
+        let mut len = c.len_utf8();
+        match (&quote, c) {
+            (Quote::None, '\\') | (Quote::Double, '\\') => {
+                // The escaped character is copied along with the backslash.
+                len += rest[len..].chars().next().map_or(0, char::len_utf8);
+            }
+            (Quote::None, '\'') => quote = Quote::Single,
+            (Quote::None, '"') => quote = Quote::Double,
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            _ => {}
+        }
+        out.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    out
+}
+
+/// The environment variable a command's argument is handed to its stages in,
+/// e.g. `TOASTER_ARG_DRY_RUN` for `dry-run`.
+pub fn arg_env_var(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect::<String>();
+    format!("TOASTER_ARG_{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_values_survive_another_pass() {
+        let value = "${date} ${FOO} $${BAR} $HOME";
+        let command = expand("echo ${v}", false, |_| Some(escape(value))).unwrap();
+        assert_eq!(expand_runtime(&command, Local::now()).unwrap(), format!("echo {}", value));
+    }
+
+    #[test]
+    fn arg_references_are_quoted_for_where_they_are() {
+        assert_eq!(
+            quote_arg_references("echo ${args.msg}"),
+            "echo \"$${TOASTER_ARG_MSG}\""
+        );
+        assert_eq!(
+            quote_arg_references("echo \"hi ${args.msg}!\""),
+            "echo \"hi $${TOASTER_ARG_MSG}!\""
+        );
+        assert_eq!(
+            quote_arg_references("echo 'hi ${args.dry-run}'"),
+            "echo 'hi '\"$${TOASTER_ARG_DRY_RUN}\"''"
+        );
+        assert_eq!(
+            quote_arg_references("echo \\' ${args.msg} $${args.msg}"),
+            "echo \\' \"$${TOASTER_ARG_MSG}\" $${args.msg}"
+        );
+    }
+
+    #[test]
+    fn runtime_expansion_unescapes_arg_references() {
+        let now = Local::now();
+        assert_eq!(
+            expand_runtime("echo ${args.msg} $${args.msg}", now).unwrap(),
+            "echo \"${TOASTER_ARG_MSG}\" ${args.msg}"
+        );
+    }
+}
@@ -1,231 +1,495 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use crate::{
     color::Color,
+    command_args::ArgSpec,
     command_builder::CommandBuilder,
+    environment::{parse_env_file, Environment},
+    template::{defer_runtime, escape, expand, is_runtime},
+    config::{
+        ArgConfig, CommandConfig, Config, EnvConfig, ConfigError, Entry, OnFailureConfig, RetryConfig,
+        SettingsConfig, Source, StageConfig, SystemConfig, SystemMode,
+    },
     settings::Settings,
-    system_builder::SystemBuilder,
-    system_stage::{SStage, Schedule},
+    system_builder::{OnFailure, Pipeline, SystemBuilder},
+    system_stage::{RetryPolicy, SStage, Schedule},
 };
-use toml::Value;
 
-pub struct TomlParser<'a> {
-    pub content: &'a str,
-    parsed_content: Option<toml::Value>,
+/// The systems, commands and settings a config describes.
+pub type Package = (
+    Option<Vec<(String, SystemBuilder)>>,
+    Option<Vec<(String, CommandBuilder)>>,
+    Settings,
+);
+
+pub struct TomlParser {
+    config: Config,
+    errors: ConfigError,
 }
 
-impl<'a> TomlParser<'a> {
-    pub fn new(content: &'a str) -> Self {
-        let value = toml::from_str::<toml::Value>(content)
-            .map_err(|e| format!("Failed to parse TOML {:?}", e))
-            .unwrap();
-        Self {
-            content,
-            parsed_content: Some(value),
-        }
+impl TomlParser {
+    /// Parses a config from a string. `include` isn't followed, since there's no file to be relative to.
+    pub fn new(content: &str) -> Self {
+        let (config, errors) = Config::parse(content);
+        Self { config, errors }
     }
 
-    fn get_settings(&self) -> Result<Settings, String> {
-        let value = self.parsed_content.as_ref().unwrap();
-        let table = value.as_table().unwrap();
+    /// Reads the config file at `path` along with every file it includes.
+    pub fn load(path: &Path) -> Self {
+        let (config, errors) = Config::load(path);
+        Self { config, errors }
+    }
 
-        let settings = table.get("settings").unwrap();
-        let threads = settings.get("threads").unwrap().as_integer().unwrap() as usize;
-        let default_row_length = settings
-            .get("default_row_length")
-            .unwrap()
-            .as_integer()
-            .unwrap() as usize;
-        Ok(Settings::new(threads, default_row_length))
+    /// Every file the config was read from, starting with the root.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.config.files
     }
 
-    pub fn parse(
-        &self,
-    ) -> Result<
-        (
-            Option<Vec<(String, SystemBuilder)>>,
-            Option<Vec<(String, CommandBuilder)>>,
-            Settings,
-        ),
-        String,
-    > {
-        let systems = self.return_as_system();
-        let commands = self.return_as_command().unwrap();
-        let settings = self.get_settings()?;
-        Ok((systems, commands, settings))
-    }
-
-    fn return_as_system(&self) -> Option<Vec<(String, SystemBuilder)>> {
-        let parsed_content = self.parsed_content.as_ref().unwrap();
-        let table = parsed_content.as_table().unwrap();
-
-        let toml_systems = match table.get("system") {
-            Some(cmd) => cmd.as_table().unwrap(),
-            None => return None,
-        };
+    /// What was read from each of `files`.
+    pub fn sources(&self) -> &[Arc<Source>] {
+        &self.config.sources
+    }
 
-        let mut systems: Vec<(String, SystemBuilder)> = Vec::new();
+    fn get_settings(&self) -> Settings {
+        let config = self.config.settings();
+        let mut settings = Settings::new(config.threads, config.default_row_length);
+        settings.shutdown_timeout = config.shutdown_timeout;
+        settings.socket = config.socket;
+        settings.logs = config.logs.settings(config.log_format);
+        settings
+    }
 
-        for (key, value) in toml_systems.iter() {
-            let name = key.to_string();
-            println!("Processing system: {}", name);
-            let description = value
-                .get("description")
-                .expect("System doesn't have a description")
-                .as_str()
-                .unwrap()
-                .to_string();
-            let shell = value
-                .get("shell")
-                .expect("System doesn't have a shell")
-                .as_str()
-                .unwrap()
-                .to_string();
-            let stages = value
-                .get("stages")
-                .expect("System doesn't have stages; expected an array.")
-                .as_array()
-                .map(|a| {
-                    a.iter()
-                        .map(|s| s.as_str().unwrap().to_string())
-                        .collect::<Vec<String>>()
-                })
-                .unwrap();
-            let schedules = value
-                .get("schedules")
-                .expect("System doesn't have a schedule")
-                .as_array()
-                .map(|a| {
-                    a.iter()
-                        .map(|s| s.as_str().unwrap().to_string())
-                        .collect::<Vec<String>>()
-                })
-                .unwrap();
+    /// Builds the systems, commands and settings, or reports every problem found in the config.
+    /// Warnings don't stop the config from loading and are only printed.
+    pub fn parse(&self) -> Result<Package, ConfigError> {
+        let (package, errors, warnings) = self.build();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
 
-            let mut system_builder = SystemBuilder::new(name.to_owned(), description, shell);
+        for problem in &warnings.problems {
+            eprintln!("Warning: {}", problem);
+        }
+        for (name, _) in package.0.iter().flatten() {
+            println!("Processing system: {}", name);
+        }
+        for (name, _) in package.1.iter().flatten() {
+            println!("Processing command: {}", name);
+        }
+        Ok(package)
+    }
 
-            for (stage, schedule) in stages.iter().zip(schedules.iter()) {
-                let stage = SStage::new(
-                    stage.to_owned(),
-                    Schedule::from_str(schedule.as_str()).unwrap(),
-                );
-                system_builder.add_stage(stage);
+    /// Lints the config without loading it, returning its errors, its warnings and any shells
+    /// that can't be found on `PATH`.
+    pub fn check(&self) -> ConfigError {
+        let (_, mut problems, warnings) = self.build();
+        problems.problems.extend(warnings.problems);
+
+        for (name, entry) in &self.config.systems {
+            if !shell_exists(&entry.value.shell) {
+                let message = format!("shell '{}' wasn't found on PATH", entry.value.shell);
+                let path = format!("system.{}.shell", name);
+                problems.problems.push(entry.problem(path, "shell", message));
+            }
+        }
+        for (name, entry) in &self.config.commands {
+            if !shell_exists(&entry.value.shell) {
+                let message = format!("shell '{}' wasn't found on PATH", entry.value.shell);
+                let path = format!("command.{}.shell", name);
+                problems.problems.push(entry.problem(path, "shell", message));
             }
+        }
+
+        let files = &self.config.files;
+        problems.problems.sort_by_key(|problem| {
+            let file = problem.file.as_ref().and_then(|f| files.iter().position(|x| x == f));
+            (file, problem.line)
+        });
+        problems
+    }
 
-            systems.push((name, system_builder));
+    /// Builds the config, returning what was built alongside its errors and warnings.
+    fn build(&self) -> (Package, ConfigError, ConfigError) {
+        let mut errors = self.errors.clone();
+        let mut warnings = ConfigError {
+            problems: self.config.unknown_keys.clone(),
+        };
+        let env = match &self.config.settings {
+            Some(settings) => environment(
+                settings,
+                "settings",
+                &settings.value.env(),
+                &Environment::default(),
+                &mut errors,
+                &mut warnings,
+            ),
+            None => Environment::default(),
+        };
+        if let Some(settings) = &self.config.settings {
+            check_settings(settings, &mut errors);
+        }
+        let systems = self.return_as_system(&env, &mut errors, &mut warnings);
+        let commands = self.return_as_command(&env, &mut errors, &mut warnings);
+        let settings = self.get_settings();
+        ((systems, commands, settings), errors, warnings)
+    }
+
+    fn return_as_system(
+        &self,
+        env: &Environment,
+        errors: &mut ConfigError,
+        warnings: &mut ConfigError,
+    ) -> Option<Vec<(String, SystemBuilder)>> {
+        if self.config.systems.is_empty() {
+            return None;
+        }
+
+        let mut systems: Vec<(String, SystemBuilder)> = Vec::new();
+
+        for (name, entry) in self.config.systems.iter() {
+            if let Some(system_builder) = self.system(name, entry, env, errors, warnings) {
+                systems.push((name.to_owned(), system_builder));
+            }
         }
 
         Option::from(systems)
     }
 
-    fn return_as_command(&self) -> Result<Option<Vec<(String, CommandBuilder)>>, String> {
-        let parsed_content = self.parsed_content.as_ref().unwrap();
-        let table = parsed_content.as_table().unwrap();
+    fn system(
+        &self,
+        name: &str,
+        entry: &Entry<SystemConfig>,
+        env: &Environment,
+        errors: &mut ConfigError,
+        warnings: &mut ConfigError,
+    ) -> Option<SystemBuilder> {
+        let system = &entry.value;
+        if system.mode == SystemMode::Independent && system.stages.len() != system.schedules.len() {
+            let message = format!(
+                "{} stage(s) but {} schedule(s); only the first {} will run",
+                system.stages.len(),
+                system.schedules.len(),
+                system.stages.len().min(system.schedules.len())
+            );
+            let path = format!("system.{}.schedules", name);
+            warnings.problems.push(entry.problem(path, "schedules", message));
+        }
+        let problems = errors.problems.len();
+        let path = format!("system.{}", name);
+        let env = environment(entry, &path, &system.env(), env, errors, warnings);
+        let mut report = |key: &str, message: String| {
+            let path = format!("system.{}.{}", name, key);
+            errors.problems.push(entry.problem(path, key, message));
+        };
+        let expand_stage = |stage: &StageConfig, index: usize| {
+            self.expand_stage(&stage.command, "system", name, index, &env, &[])
+        };
+
+        let mut system_builder =
+            SystemBuilder::new(name.to_owned(), system.description.clone(), system.shell.clone());
+        system_builder.set_catch_up(system.catch_up);
+        system_builder.set_timeout(system.timeout.map(|t| t.0));
+        if let Some(retry) = retry_policy(&system.retry(), &system_builder.retry) {
+            system_builder.set_retry(retry);
+        }
 
-        let toml_commands = match table.get("command") {
-            Some(cmd) => cmd.as_table().unwrap(),
-            None => return Ok(None),
+        let mut parse_schedule = |key: &str, schedule: &str| match Schedule::from_str(schedule) {
+            Ok(schedule) => Some(schedule),
+            Err(e) => {
+                report(key, format!("invalid schedule '{}': {}", schedule, e));
+                None
+            }
         };
 
-        let mut commands: Vec<(String, CommandBuilder)> = Vec::new();
+        // A pipeline has one schedule for all of its stages, so it's parsed once.
+        let mut pipeline_schedule = None;
+        let parsed = match system.mode {
+            SystemMode::Pipeline => {
+                match &system.schedule {
+                    Some(schedule) => pipeline_schedule = parse_schedule("schedule", schedule),
+                    None => report("mode", "a pipeline needs a `schedule`".to_string()),
+                }
+                if system.stages.is_empty() {
+                    report("stages", "a pipeline needs at least one stage".to_string());
+                }
+                match &pipeline_schedule {
+                    Some(schedule) => vec![schedule.clone(); system.stages.len()],
+                    None => Vec::new(),
+                }
+            }
+            SystemMode::Independent => system
+                .schedules
+                .iter()
+                .filter_map(|schedule| parse_schedule("schedules", schedule))
+                .collect(),
+        };
 
-        for (key, value) in toml_commands.iter() {
-            let name = key.to_string();
-            println!("Processing command: {}", name);
-            let description = value
-                .get("description")
-                .expect("Command doesn't have a description")
-                .as_str()
-                .unwrap()
-                .to_string();
-            let shell = value
-                .get("shell")
-                .expect("Command doesn't have a shell")
-                .as_str()
-                .unwrap()
-                .to_string();
-
-            let mut command_builder = CommandBuilder::new(name.to_owned(), description, shell);
-
-            // Handle stages
-            if let Some(toml_stages) = value.get("stages").and_then(Value::as_array) {
-                for stage in toml_stages {
-                    if let Some(stage_str) = stage.as_str() {
-                        // Check for '%' at the beginning
-                        if stage_str.starts_with('%') {
-                            // Extract parameters
-                            if let Some(params_str) = stage_str
-                                .strip_prefix('%')
-                                .and_then(|s| s.split_once('[').map(|(_, params)| params.trim()))
-                            {
-                                let (params, rest_of_command) = params_str.split_once(']').unwrap();
-                                let params_vec = params.split(',').collect::<Vec<&str>>();
-                                let spep = self.handle_special_parameters_cmd(
-                                    &mut command_builder,
-                                    &params_vec,
-                                );
-
-                                if !rest_of_command.is_empty() {
-                                    command_builder.add_stage(
-                                        rest_of_command.to_string(),
-                                        spep.color,
-                                        spep.sorted,
-                                    );
-                                }
-                            } else {
-                                eprintln!("Failed to extract parameters from: {}", stage_str);
+        for (index, (stage, schedule)) in system.stages.iter().zip(parsed.iter()).enumerate() {
+            let mut sstage = system_stage(stage, schedule.clone(), &system_builder.retry);
+            match expand_stage(stage, index + 1) {
+                Ok(command) => sstage.command = command,
+                Err(e) => report(&format!("stages[{}]", index), e),
+            }
+            system_builder.add_stage(sstage);
+        }
+
+        if system.mode == SystemMode::Pipeline {
+            if let Some(schedule) = &pipeline_schedule {
+                let on_failure = match system.on_failure {
+                    OnFailureConfig::Abort => Some(OnFailure::Abort),
+                    OnFailureConfig::Continue => Some(OnFailure::Continue),
+                    OnFailureConfig::Cleanup => match &system.cleanup {
+                        Some(cleanup) => {
+                            let mut sstage =
+                                system_stage(cleanup, schedule.clone(), &system_builder.retry);
+                            match expand_stage(cleanup, system.stages.len() + 1) {
+                                Ok(command) => sstage.command = command,
+                                Err(e) => report("cleanup", e),
                             }
-                        } else {
-                            command_builder.add_stage(stage_str.to_string(), Color::White, None);
+                            Some(OnFailure::Cleanup(sstage))
                         }
-                    } else {
-                        return Err("Invalid stage format; expected string.".to_string());
-                    }
+                        None => {
+                            report(
+                                "on_failure",
+                                "on_failure = \"cleanup\" needs a `cleanup` stage".to_string(),
+                            );
+                            None
+                        }
+                    },
+                };
+                if let Some(on_failure) = on_failure {
+                    system_builder.set_pipeline(Some(Pipeline {
+                        schedule: schedule.clone(),
+                        on_failure,
+                    }));
                 }
-            } else {
-                return Err("Command doesn't have stages; expected an array.".to_string());
             }
+        }
+        system_builder.set_env(env);
+
+        if errors.problems.len() > problems {
+            return None;
+        }
+        Some(system_builder)
+    }
+
+    fn return_as_command(
+        &self,
+        env: &Environment,
+        errors: &mut ConfigError,
+        warnings: &mut ConfigError,
+    ) -> Option<Vec<(String, CommandBuilder)>> {
+        if self.config.commands.is_empty() {
+            return None;
+        }
+
+        let mut commands: Vec<(String, CommandBuilder)> = Vec::new();
+
+        for (name, entry) in self.config.commands.iter() {
+            let problems = errors.problems.len();
+            let path = format!("command.{}", name);
+            let env = environment(entry, &path, &entry.value.env(), env, errors, warnings);
+
+            let args = match command_args(&entry.value.args) {
+                Ok(args) => args,
+                Err((index, message)) => {
+                    let path = format!("command.{}.args[{}]", name, index);
+                    errors.problems.push(entry.problem(path, "args", message));
+                    continue;
+                }
+            };
+
+            let mut stage_errors = Vec::new();
+            let mut stage_warnings = Vec::new();
+            let mut command_builder = self.command(
+                name,
+                &entry.value,
+                &env,
+                &args,
+                &mut stage_errors,
+                &mut stage_warnings,
+            );
+            for (stage, message) in stage_errors {
+                let path = format!("command.{}.stages[{}]", name, stage);
+                errors.problems.push(entry.problem(path, "stages", message));
+            }
+            for (stage, message) in stage_warnings {
+                let path = format!("command.{}.stages[{}]", name, stage);
+                warnings.problems.push(entry.problem(path, "stages", message));
+            }
+            if errors.problems.len() > problems {
+                continue;
+            }
+
+            command_builder.env = env;
+            command_builder.args = args;
 
             // Check if command_builder has stages added
             if command_builder.stages.len() > 0 {
-                commands.push((name, command_builder));
+                commands.push((name.to_owned(), command_builder));
             } else {
-                eprintln!("Command '{}' has no stages.", name);
+                let path = format!("command.{}.stages", name);
+                warnings
+                    .problems
+                    .push(entry.problem(path, "stages", "command has no stages"));
             }
         }
 
-        Ok(Some(commands))
+        Some(commands)
     }
 
-    /// Handle special parameters and modify the CommandBuilder accordingly.
+    /// Builds a command. Problems with its stages are added to `errors` and `warnings`
+    /// along with the index of the stage.
+    fn command(
+        &self,
+        name: &str,
+        command: &CommandConfig,
+        env: &Environment,
+        args: &[ArgSpec],
+        errors: &mut Vec<(usize, String)>,
+        warnings: &mut Vec<(usize, String)>,
+    ) -> CommandBuilder {
+        let mut command_builder =
+            CommandBuilder::new(name.to_owned(), command.description.clone(), command.shell.clone());
+
+        for (index, stage_str) in command.stages.iter().enumerate() {
+            // Check for '%' at the beginning
+            let (stage_str, spep) = if stage_str.starts_with('%') {
+                // Extract parameters
+                let Some((params, rest_of_command)) = stage_str
+                    .strip_prefix('%')
+                    .and_then(|s| s.split_once('[').map(|(_, params)| params.trim()))
+                    .and_then(|params| params.split_once(']'))
+                else {
+                    let message = "stage parameters must be written as %[param,...]";
+                    errors.push((index, message.to_string()));
+                    continue;
+                };
+                let params_vec = params.split(',').collect::<Vec<&str>>();
+                let mut param_warnings = Vec::new();
+                let spep = match self.handle_special_parameters_cmd(&params_vec, &mut param_warnings)
+                {
+                    Ok(spep) => spep,
+                    Err(e) => {
+                        errors.push((index, e));
+                        continue;
+                    }
+                };
+                warnings.extend(param_warnings.into_iter().map(|w| (index, w)));
+
+                if rest_of_command.is_empty() {
+                    continue;
+                }
+                (rest_of_command, spep)
+            } else {
+                (stage_str.as_str(), SpecialFields::new(Color::White, None))
+            };
+
+            let stage_index = command_builder.stages.len() + 1;
+            match self.expand_stage(stage_str, "command", name, stage_index, env, args) {
+                Ok(stage_str) => command_builder.add_stage(stage_str, spep.color, spep.sorted),
+                Err(e) => errors.push((index, e)),
+            }
+        }
+
+        command_builder
+    }
+
+    /// Expands the `${...}` references in a stage's command that can be resolved now:
+    /// `[vars]`, environment variables and built-ins like `${system.name}`.
+    /// Built-ins that change between runs, like `${date}`, and `${args.name}` for the
+    /// command's declared `args` are left for when the stage runs.
+    fn expand_stage(
+        &self,
+        command: &str,
+        kind: &str,
+        name: &str,
+        stage_index: usize,
+        env: &Environment,
+        args: &[ArgSpec],
+    ) -> Result<String, String> {
+        let owner_name = format!("{}.name", kind);
+        let vars = self.config.vars.as_ref().map(|vars| &vars.value);
+
+        // Values are escaped so the pass when the stage runs doesn't read them again.
+        expand(command, false, |var| match var {
+            "stage.index" => Some(stage_index.to_string()),
+            "toaster.home" => Some(escape(&self.home().display().to_string())),
+            var if var == owner_name => Some(escape(name)),
+            var if is_runtime(var) => defer_runtime(var),
+            var if var.starts_with("args.") => args
+                .iter()
+                .any(|arg| var[5..] == arg.name)
+                .then(|| format!("${{{}}}", var)),
+            var => vars
+                .and_then(|vars| vars.get(var))
+                .map(|value| value.0.clone())
+                .or_else(|| env.vars.get(var).cloned())
+                .or_else(|| match env.clear_env {
+                    true => None,
+                    false => std::env::var(var).ok(),
+                })
+                .map(|value| escape(&value)),
+        })
+    }
+
+    /// The directory the root config is in, which `${toaster.home}` expands to.
+    fn home(&self) -> PathBuf {
+        let dir = self
+            .config
+            .files
+            .first()
+            .and_then(|file| file.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
+    }
+
+    /// Handle special parameters, adding any that are ignored to `warnings`.
     fn handle_special_parameters_cmd(
         &self,
-        command_builder: &mut CommandBuilder,
         params: &[&str],
-    ) -> SpecialFields {
+        warnings: &mut Vec<String>,
+    ) -> Result<SpecialFields, String> {
         let mut color = Color::White;
         let mut sorted = false; // Whether to sort the output
-        let mut elements_per_line: usize = self.get_settings().unwrap().default_row_length;
+        let mut elements_per_line: usize = self.config.settings().default_row_length;
 
         for param in params {
             if param.starts_with("color:") {
                 let colorr = param.strip_prefix("color:").unwrap().to_string();
                 let colorrr = Color::from_str(&colorr);
+                if colorrr.is_none() {
+                    warnings.push(format!("unknown color '{}'; using white", colorr));
+                }
                 color = colorrr.unwrap_or(Color::White);
                 continue;
             }
 
             if param.starts_with("o") {
                 sorted = true;
-                let flags_string = param.strip_prefix("o:").unwrap().to_string();
+                let flags_string = param.strip_prefix("o:").unwrap_or_default().to_string();
                 let flags = flags_string.split(';').collect::<Vec<&str>>();
 
                 for flag in flags {
                     if flag == "-s" {
                         sorted = false;
+                    } else if !flag.is_empty() && !flag.starts_with("l") {
+                        warnings.push(format!("unknown output flag '{}' in '{}'", flag, param));
                     }
 
                     if flag.starts_with("l") {
-                        elements_per_line =
-                            flag.strip_prefix("l").unwrap().parse::<usize>().unwrap();
+                        let length = flag.strip_prefix("l").unwrap_or_default();
+                        elements_per_line = length
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|length| *length >= 1)
+                            .ok_or_else(|| {
+                                format!("invalid row length '{}' in '{}'", length, param)
+                            })?;
                     }
                 }
 
@@ -233,8 +497,9 @@ impl<'a> TomlParser<'a> {
             }
 
             match *param {
+                "" => {}
                 _ => {
-                    eprintln!("Unknown parameter: {}", param);
+                    warnings.push(format!("unknown parameter '{}'", param));
                 }
             }
         }
@@ -242,10 +507,189 @@ impl<'a> TomlParser<'a> {
         let sort_rules = SortRules::new(elements_per_line, sorted);
         let special_fields = SpecialFields::new(color, Some(sort_rules));
 
-        return special_fields;
+        Ok(special_fields)
+    }
+}
+
+/// Checks the settings that can't be zero: a pool with no threads would never run a system,
+/// and output can't be split into rows of no items.
+fn check_settings(settings: &Entry<SettingsConfig>, errors: &mut ConfigError) {
+    let counts = [
+        ("threads", settings.value.threads),
+        ("default_row_length", settings.value.default_row_length),
+    ];
+    for (key, value) in counts {
+        if value < 1 {
+            let path = format!("settings.{}", key);
+            let message = format!("{} must be at least 1", key);
+            errors.problems.push(settings.problem(path, key, message));
+        }
     }
 }
 
+/// Resolves the environment an entry sets on top of `base`.
+/// `env_file` and `cwd` are relative to the file the entry is in.
+fn environment<T>(
+    entry: &Entry<T>,
+    path: &str,
+    config: &EnvConfig,
+    base: &Environment,
+    errors: &mut ConfigError,
+    warnings: &mut ConfigError,
+) -> Environment {
+    let mut env = base.clone();
+    let dir = entry
+        .source
+        .file
+        .as_deref()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    if let Some(clear_env) = config.clear_env {
+        env.clear_env = clear_env;
+    }
+
+    if let Some(env_file) = &config.env_file {
+        let env_file = dir.join(env_file);
+        let vars = std::fs::read_to_string(&env_file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| parse_env_file(&content));
+        match vars {
+            Ok(vars) => env.vars.extend(vars),
+            Err(e) => {
+                let message = format!("couldn't read {}: {}", env_file.display(), e);
+                let problem = entry.problem(format!("{}.env_file", path), "env_file", message);
+                errors.problems.push(problem);
+            }
+        }
+    }
+
+    env.vars
+        .extend(config.env.iter().map(|(key, value)| (key.clone(), value.0.clone())));
+
+    if let Some(cwd) = &config.cwd {
+        let cwd = dir.join(cwd);
+        if !cwd.is_dir() {
+            let message = format!("{} isn't a directory", cwd.display());
+            warnings
+                .problems
+                .push(entry.problem(format!("{}.cwd", path), "cwd", message));
+        }
+        env.cwd = Some(cwd);
+    }
+
+    env
+}
+
+/// Checks a command's declared arguments, or returns the index of the one that's wrong and why.
+fn command_args(args: &[ArgConfig]) -> Result<Vec<ArgSpec>, (usize, String)> {
+    let mut specs: Vec<ArgSpec> = Vec::new();
+
+    for (index, arg) in args.iter().enumerate() {
+        let fail = |message: String| (index, message);
+        let valid_name = arg
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if arg.name.is_empty() || !valid_name {
+            return Err(fail(format!(
+                "invalid argument name '{}'; use letters, digits, '-' and '_'",
+                arg.name
+            )));
+        }
+        if specs.iter().any(|spec| spec.name == arg.name) {
+            return Err(fail(format!("argument '{}' is declared twice", arg.name)));
+        }
+        if arg.required && arg.default.is_some() {
+            return Err(fail(format!(
+                "argument '{}' is required, so its default would never be used",
+                arg.name
+            )));
+        }
+
+        let choices = arg
+            .choices
+            .iter()
+            .map(|choice| arg.kind.check(&choice.0))
+            .collect::<Result<Vec<String>, String>>()
+            .map_err(|e| fail(format!("choices: {}", e)))?;
+        let default = match &arg.default {
+            Some(default) => {
+                let default = arg
+                    .kind
+                    .check(&default.0)
+                    .map_err(|e| fail(format!("default: {}", e)))?;
+                if !choices.is_empty() && !choices.contains(&default) {
+                    return Err(fail(format!(
+                        "default: '{}' isn't one of {}",
+                        default,
+                        choices.join(", ")
+                    )));
+                }
+                Some(default)
+            }
+            None => None,
+        };
+        specs.push(ArgSpec {
+            name: arg.name.clone(),
+            kind: arg.kind,
+            required: arg.required,
+            choices,
+            default,
+            description: arg.description.clone(),
+        });
+    }
+
+    Ok(specs)
+}
+
+/// Whether `shell` is a path to a file, or the name of one on `PATH`.
+fn shell_exists(shell: &str) -> bool {
+    if shell.contains('/') {
+        return Path::new(shell).is_file();
+    }
+
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| dir.join(shell).is_file())
+    })
+}
+
+/// Builds a system stage from its config, inheriting the system's retry policy.
+fn system_stage(stage: &StageConfig, schedule: Schedule, system_retry: &RetryPolicy) -> SStage {
+    let mut sstage = SStage::new(stage.command.clone(), schedule);
+    sstage.timeout = stage.timeout.map(|t| t.0);
+    sstage.retry = retry_policy(&stage.retry, system_retry);
+    sstage
+}
+
+/// Applies `retries`, `retry_delay`, `retry_backoff` and `max_retry_delay` on top of `base`.
+/// Returns `None` if none of them are set.
+fn retry_policy(config: &RetryConfig, base: &RetryPolicy) -> Option<RetryPolicy> {
+    if config.retries.is_none()
+        && config.retry_delay.is_none()
+        && config.retry_backoff.is_none()
+        && config.max_retry_delay.is_none()
+    {
+        return None;
+    }
+
+    let mut retry = base.clone();
+    if let Some(retries) = config.retries {
+        retry.retries = retries;
+    }
+    if let Some(delay) = config.retry_delay {
+        retry.delay = delay.0;
+    }
+    if let Some(backoff) = config.retry_backoff {
+        retry.backoff = backoff;
+    }
+    if let Some(max_delay) = config.max_retry_delay {
+        retry.max_delay = Some(max_delay.0);
+    }
+    Some(retry)
+}
+
 #[derive(Debug, Clone)]
 pub struct SpecialFields {
     pub color: Color,
@@ -261,7 +705,7 @@ impl SpecialFields {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SortRules {
     pub sorting: bool,
     pub items_per_line: usize,
@@ -275,3 +719,133 @@ impl SortRules {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::expand_runtime;
+
+    fn system_command(content: &str) -> String {
+        let (systems, _, _) = TomlParser::new(content).parse().unwrap();
+        let (_, system) = &systems.unwrap()[0];
+        expand_runtime(&system.stages[&1].command, chrono::Local::now()).unwrap()
+    }
+
+    #[test]
+    fn vars_are_only_expanded_once() {
+        let command = system_command(
+            r#"
+            [vars]
+            literal = "${date} and ${FOO} and $${BAR}"
+
+            [system.test]
+            stages = ["echo '${literal}' ${system.name}"]
+            schedules = ["00:00:00:00:30"]
+            "#,
+        );
+        assert_eq!(command, "echo '${date} and ${FOO} and $${BAR}' test");
+    }
+
+    #[test]
+    fn runtime_references_are_kept_for_when_the_stage_runs() {
+        let command = system_command(
+            r#"
+            [system.test]
+            stages = ["echo ${date:%Y} $${literal}"]
+            schedules = ["00:00:00:00:30"]
+            "#,
+        );
+        let year = chrono::Local::now().format("%Y").to_string();
+        assert_eq!(command, format!("echo {} ${{literal}}", year));
+    }
+
+    fn errors(content: &str) -> Vec<String> {
+        let errors = TomlParser::new(content).parse().unwrap_err();
+        errors.problems.iter().map(|problem| problem.to_string()).collect()
+    }
+
+    #[test]
+    fn threads_must_be_at_least_one() {
+        let errors = errors(
+            r#"
+            [settings]
+            threads = 0
+            "#,
+        );
+        assert_eq!(errors, ["line 3: settings.threads: threads must be at least 1"]);
+    }
+
+    #[test]
+    fn default_row_length_must_be_at_least_one() {
+        let errors = errors(
+            r#"
+            [settings]
+            threads = 2
+            default_row_length = 0
+            "#,
+        );
+        assert_eq!(
+            errors,
+            ["line 4: settings.default_row_length: default_row_length must be at least 1"]
+        );
+    }
+
+    #[test]
+    fn row_length_parameters_must_be_at_least_one() {
+        let errors = errors(
+            r#"
+            [command.list]
+            stages = ["%[o:l0] ls"]
+            "#,
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("line 3: command.list.stages[0]"), "{}", errors[0]);
+        assert!(errors[0].contains("invalid row length '0' in 'o:l0'"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn a_pipeline_schedule_is_reported_once() {
+        let errors = errors(
+            r#"
+            [system.deploy]
+            mode = "pipeline"
+            schedule = "every now and then"
+            stages = ["git pull", "make", "make install"]
+            "#,
+        );
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let expected = "line 4: system.deploy.schedule: invalid schedule 'every now and then'";
+        assert!(errors[0].starts_with(expected), "{}", errors[0]);
+    }
+
+    #[test]
+    fn a_pipeline_needs_stages() {
+        let errors = errors(
+            r#"
+            [system.deploy]
+            mode = "pipeline"
+            schedule = "0 * * * *"
+            stages = []
+            "#,
+        );
+        assert_eq!(
+            errors,
+            ["line 5: system.deploy.stages: a pipeline needs at least one stage"]
+        );
+    }
+
+    #[test]
+    fn required_args_cant_have_a_default() {
+        let errors = TomlParser::new(
+            r#"
+            [command.deploy]
+            stages = ["echo ${args.env}"]
+            args = [{ name = "env", required = true, default = "dev" }]
+            "#,
+        )
+        .parse()
+        .unwrap_err();
+        assert!(errors.to_string().contains("command.deploy.args[0]"), "{}", errors);
+        assert!(errors.to_string().contains("default would never be used"), "{}", errors);
+    }
+}
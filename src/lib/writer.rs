@@ -0,0 +1,120 @@
+use std::{
+    fmt::{Display, Formatter},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    time::{Instant, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    logs::LogWriter,
+    output::{OutputQueue, QueuedEntry},
+};
+
+/// What a flush wrote to the log files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlushReport {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl Display for FlushReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} entries ({} bytes)", self.entries, self.bytes)
+    }
+}
+
+/// Sent to the output writer's thread.
+#[derive(Debug)]
+pub enum WriterRequest {
+    /// Write the queue out now and send back how it went.
+    Flush(Sender<Result<FlushReport, String>>),
+    /// `max_queue_size` entries are waiting.
+    QueueFull,
+}
+
+/// Owns writing the output queue to the log files, on its own thread.
+///
+/// It writes the queue out when asked to, every `flush_interval`, or once the queue is full.
+/// Entries that couldn't be written are kept, in memory and in the spool, and tried again on the next flush.
+pub struct OutputWriter {
+    pub output_queue: OutputQueue,
+    pub log_writer: Arc<RwLock<LogWriter>>,
+    pub last_flush_output: Arc<RwLock<Option<String>>>,
+    pub last_flush_at: Arc<RwLock<Option<u64>>>,
+    /// Entries taken from the queue that haven't been written yet, oldest first.
+    pub unwritten: Vec<QueuedEntry>,
+    pub requests: Receiver<WriterRequest>,
+}
+
+impl OutputWriter {
+    pub fn spawn(mut self) {
+        std::thread::spawn(move || {
+            let mut last_flush = Instant::now();
+            loop {
+                let flush_interval = self.log_writer.read().unwrap().settings.flush_interval;
+                let timeout = flush_interval.saturating_sub(last_flush.elapsed());
+                let reply = match self.requests.recv_timeout(timeout) {
+                    Ok(WriterRequest::Flush(reply)) => Some(reply),
+                    Ok(WriterRequest::QueueFull) | Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                if reply.is_none() && !self.output_queue.is_empty() {
+                    println!("Auto-Flushing output...");
+                }
+
+                let result = self.flush();
+                last_flush = Instant::now();
+                match reply {
+                    // The client may have given up waiting, which is fine.
+                    Some(reply) => drop(reply.send(result)),
+                    None => {
+                        if let Err(e) = result {
+                            eprintln!("{}", e);
+                        }
+                    }
+                }
+                // Compressing and deleting old files can take a while, so it's done after replying.
+                self.log_writer.read().unwrap().maintain();
+            }
+        });
+    }
+
+    /// Writes everything waiting to the log files.
+    fn flush(&mut self) -> Result<FlushReport, String> {
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let mut entries = std::mem::take(&mut self.unwritten);
+        entries.extend(self.output_queue.drain());
+
+        let log_writer = self.log_writer.read().unwrap();
+        let result = match log_writer.write(&entries) {
+            Ok(bytes) => {
+                let text = entries
+                    .iter()
+                    .map(|(_, entry)| entry.to_string())
+                    .collect::<Vec<String>>();
+                self.last_flush_output.write().unwrap().replace(text.join("\n"));
+                self.last_flush_at.write().unwrap().replace(now);
+                Ok(FlushReport {
+                    entries: entries.len(),
+                    bytes,
+                })
+            }
+            Err(e) => {
+                let error = format!(
+                    "Failed to write to the log files: {}; {} entries will be retried on the next flush",
+                    e,
+                    entries.len()
+                );
+                self.unwritten = entries;
+                Err(error)
+            }
+        };
+
+        self.output_queue.compact_spool(&self.unwritten);
+        result
+    }
+}
@@ -1,116 +1,424 @@
 use std::{
-    io::{BufRead, Read, Write}, os::unix::net::UnixStream, path::PathBuf, time::Duration
+    io::Write,
+    path::PathBuf,
+    sync::OnceLock,
+    time::Duration,
 };
 
-use args::Arguments;
-use chrono::Datelike;
+use lib::{
+    args::Arguments,
+    config::Config,
+    info::{format_time, Listing},
+    log_query::{merge_queued, parse_time, read_logs, LogQuery},
+    paths::Paths,
+    protocol::{Connection, ProtocolError, Request, Response},
+    socket::socket_path,
+    tomlp::TomlParser,
+};
 
-pub mod args;
+/// Where the daemon's socket is, worked out once from the flags, environment and config.
+static SOCKET: OnceLock<PathBuf> = OnceLock::new();
 
-fn main() {
-    let daemon_res = UnixStream::connect("/tmp/toaster.sock");
-    let mut daemon = match daemon_res {
-        Ok(stream) => stream,
-        Err(_) => {
-            panic!("Couldn't connect to daemon server; Make sure it's running.");
-        }
+/// Prints `msg` and exits with a failure status.
+fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
+
+/// Connects to the daemon, bailing out if it isn't running or speaks another protocol version.
+fn connect() -> Connection {
+    let socket = SOCKET.get().expect("Socket path isn't set");
+    let connection = match Connection::connect(socket) {
+        Ok(connection) => connection,
+        Err(ProtocolError::Io(e)) => fail(format!(
+            "Couldn't connect to daemon server at {} ({}); Make sure it's running.",
+            socket.display(),
+            e
+        )),
+        Err(e) => fail(format!("Couldn't connect to daemon server: {}", e)),
     };
-    
-    let home = PathBuf::from(std::env::var("HOME").unwrap());
-    let toaster_path = home.join(".toaster");
-    let log_path = toaster_path.join("Logs");
-    
-    daemon
-        .set_nonblocking(false)
-        .expect("Could not set blocking");
-    daemon
+
+    connection
+        .stream
         .set_read_timeout(Some(Duration::from_secs(2)))
         .expect("Could not set read timeout");
-    daemon
+    connection
+        .stream
         .set_write_timeout(Some(Duration::from_secs(2)))
         .expect("Could not set write timeout");
+    connection
+}
+
+/// Sends a request that is answered with a single response.
+fn request(request: Request) -> Response {
+    match connect().request(&request) {
+        Ok(Response::Error(e)) => fail(e),
+        Ok(response) => response,
+        Err(e) => fail(format!("Daemon request failed: {}", e)),
+    }
+}
+
+/// Prints a value as pretty JSON, for `--json` output.
+fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => fail(format!("Couldn't encode JSON: {}", e)),
+    }
+}
+
+/// Prints rows as columns padded to the widest cell, trimming the padding off the last one.
+fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let widths = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<usize>>();
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+fn list() -> Listing {
+    match request(Request::List) {
+        Response::List(listing) => listing,
+        other => fail(ProtocolError::Unexpected(Box::new(other))),
+    }
+}
 
+fn main() {
     let mut args = Arguments::new();
+    let (socket_flag, config_flag) = args.take_global_options();
+
+    let paths = Paths::resolve(config_flag).unwrap_or_else(|e| fail(e));
+    let log_path = &paths.logs;
+    // Only the socket path is needed from the config, so problems are left to `toaster check`.
+    let configured_socket = std::fs::read_to_string(&paths.config)
+        .ok()
+        .and_then(|content| Config::parse(&content).0.settings().socket);
+    SOCKET
+        .set(socket_path(socket_flag, configured_socket.as_deref()))
+        .expect("Socket path was already set");
+
     let command = args.next().expect("No command given");
 
     match command.as_str() {
         "logs" => {
-            let next = args.next().expect("no flag provided");
-            match next.as_str() {
-                "latest" => {
-                    let year = chrono::Local::now().year();
-                    let month = chrono::Local::now().month();
-                    let day = chrono::Local::now().day();
-                    
-                    let output_file_name = format!("output-{}-{}-{}.log", year, month, day);
-                    let output_file_path = log_path.join(output_file_name);
-                    let file = std::fs::File::open(output_file_path).expect("Could not open file");
-                    let buffer = std::io::BufReader::new(file);
-                    buffer.lines().for_each(|line| {
-                        println!("{}", line.unwrap());
-                    });
-                },
-                _ => {
-                    let output_file_name = format!("output-{}.log", next);
-                    let output_file_path = log_path.join(output_file_name);
-                    let file = std::fs::File::open(output_file_path).expect("Could not open file");
-                    let buffer = std::io::BufReader::new(file);
-                    buffer.lines().for_each(|line| {
-                        println!("{}", line.unwrap());
-                    });
+            let mut query = LogQuery::default();
+            let mut tail = None;
+            let mut follow = false;
+            while let Some(arg) = args.next() {
+                let (flag, inline) = match arg.split_once('=') {
+                    Some((flag, value)) if flag.starts_with("--") => {
+                        (flag.to_string(), Some(value.to_string()))
+                    }
+                    _ => (arg.clone(), None),
+                };
+                let mut value = || {
+                    inline
+                        .clone()
+                        .or_else(|| args.next())
+                        .unwrap_or_else(|| fail(format!("{} needs a value", flag)))
+                };
+                match flag.as_str() {
+                    "--system" => query.system = Some(value()),
+                    "--since" => query.since = Some(parse_time(&value()).unwrap_or_else(|e| fail(e))),
+                    "--until" => query.until = Some(parse_time(&value()).unwrap_or_else(|e| fail(e))),
+                    "--grep" => query.grep = Some(value()),
+                    "--failed-only" => query.failed_only = true,
+                    "--tail" => {
+                        let count = value();
+                        tail = Some(count.parse::<usize>().unwrap_or_else(|_| {
+                            fail(format!("Invalid --tail '{}'; expected a number of entries", count))
+                        }));
+                    }
+                    "-f" | "--follow" => follow = true,
+                    _ => fail(format!(
+                        "Unknown argument '{}'; expected --system, --since, --until, --grep, --failed-only, --tail or --follow",
+                        arg
+                    )),
                 }
             }
-        },
-        "--reload" => {
-            daemon.write(b"reload").expect("Could not write to socket");
 
-            let mut buffer = [0; 25];
-            daemon
-                .read(&mut buffer)
-                .expect("Could not read from socket");
-            let string = String::from_utf8_lossy(&buffer).to_string();
+            // Start following before reading the files, so nothing is missed in between.
+            let mut daemon = None;
+            let mut queued = vec![];
+            if follow {
+                let mut connection = connect();
+                connection
+                    .stream
+                    .set_read_timeout(None)
+                    .expect("Could not clear read timeout");
+                match connection.request(&Request::Follow) {
+                    Ok(Response::Following(entries)) => queued = entries,
+                    Ok(Response::Error(e)) => fail(e),
+                    Ok(other) => fail(ProtocolError::Unexpected(Box::new(other))),
+                    Err(e) => fail(format!("Daemon request failed: {}", e)),
+                }
+                daemon = Some(connection);
+            }
 
-            if string != "ok" {
-                panic!("Could not reload daemon got {}", string);
-            } else {
-                println!("Reloaded!");
+            let mut entries = read_logs(log_path, &query);
+            // Entries can be flushed to the log after they were sent as queued.
+            queued.retain(|entry| query.matches(entry));
+            merge_queued(&mut entries, queued);
+            if let Some(tail) = tail {
+                entries.drain(..entries.len().saturating_sub(tail));
+            }
+            for entry in &entries {
+                println!("{}", entry.text);
+            }
+
+            let Some(mut daemon) = daemon else {
+                return;
+            };
+            loop {
+                match daemon.recv() {
+                    Ok(Response::Logged(entry)) if query.matches(&entry) => {
+                        println!("{}", entry.text);
+                        std::io::stdout().flush().expect("Could not write output");
+                    }
+                    Ok(Response::Logged(_)) => {}
+                    Ok(Response::Error(e)) => fail(e),
+                    Ok(other) => fail(ProtocolError::Unexpected(Box::new(other))),
+                    Err(e) => fail(format!("Lost connection to daemon: {}", e)),
+                }
             }
         }
-        "--flush" => {
-            daemon.write(b"flush").expect("Could not write to socket");
+        "run" => {
+            let name = args.next().expect("No command name provided");
+            let mut command_args = Vec::new();
+            while let Some(arg) = args.next() {
+                command_args.push(arg);
+            }
 
-            let mut buffer = [0; 25];
-            let len = daemon
-                .read(&mut buffer)
-                .expect("Could not read from socket");
+            let mut daemon = connect();
+            // Commands can run for a while, so wait for as long as it takes.
+            daemon
+                .stream
+                .set_read_timeout(None)
+                .expect("Could not clear read timeout");
+
+            let mut response = daemon.request(&Request::Run {
+                command: name,
+                args: command_args,
+            });
+            loop {
+                match response {
+                    Ok(Response::Stdout(text)) => {
+                        print!("{}", text);
+                        std::io::stdout().flush().expect("Could not write output");
+                    }
+                    Ok(Response::Stderr(text)) => {
+                        eprint!("{}", text);
+                    }
+                    Ok(Response::Exit(code)) => std::process::exit(code),
+                    Ok(Response::Error(e)) => fail(e),
+                    Ok(other) => fail(ProtocolError::Unexpected(Box::new(other))),
+                    Err(e) => fail(format!("Lost connection to daemon: {}", e)),
+                }
+                response = daemon.recv();
+            }
+        }
+        "help" => {
+            let name = args.next().expect("No command name provided");
+            match request(Request::Help { command: name }) {
+                Response::Help(usage) => println!("{}", usage),
+                other => fail(ProtocolError::Unexpected(Box::new(other))),
+            }
+        }
+        "list" => {
+            let mut json = false;
+            let mut kind = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--json" => json = true,
+                    "systems" | "commands" if kind.is_none() => kind = Some(arg),
+                    _ => fail(format!("Unknown argument '{}'; expected systems, commands or --json", arg)),
+                }
+            }
 
-            let string = String::from_utf8_lossy(&buffer.to_vec().as_slice()[..len]).to_string();
+            let listing = list();
+            let show_systems = kind.as_deref() != Some("commands");
+            let show_commands = kind.as_deref() != Some("systems");
+            if json {
+                match kind.as_deref() {
+                    Some("systems") => print_json(&listing.systems),
+                    Some("commands") => print_json(&listing.commands),
+                    _ => print_json(&listing),
+                }
+                return;
+            }
 
-            if string != "ok" {
-                panic!("Could not flush daemon got {}", string);
-            } else {
-                println!("Flushed!");
+            if show_systems {
+                let mut rows = vec![vec![
+                    "SYSTEM".to_string(),
+                    "STATUS".to_string(),
+                    "NEXT RUN".to_string(),
+                    "LAST RUN".to_string(),
+                    "DESCRIPTION".to_string(),
+                ]];
+                for system in &listing.systems {
+                    rows.push(vec![
+                        system.name.clone(),
+                        if system.running { "running" } else { "stopped" }.to_string(),
+                        system.next_run().map(format_time).unwrap_or("-".to_string()),
+                        system
+                            .last_run()
+                            .map(|run| format!("{}, {}", format_time(run.at), run.outcome))
+                            .unwrap_or("never".to_string()),
+                        system.description.clone(),
+                    ]);
+                }
+                print_table(&rows);
+            }
+            if show_systems && show_commands {
+                println!();
+            }
+            if show_commands {
+                let mut rows = vec![vec!["COMMAND".to_string(), "DESCRIPTION".to_string()]];
+                for command in &listing.commands {
+                    rows.push(vec![command.name.clone(), command.description.clone()]);
+                }
+                print_table(&rows);
             }
         }
-        "--ping" => {
-            daemon.write(b"ping").expect("Could not write to socket");
+        "describe" => {
+            let mut json = false;
+            let mut name = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--json" => json = true,
+                    _ if name.is_none() => name = Some(arg),
+                    _ => fail(format!("Unexpected argument '{}'", arg)),
+                }
+            }
+            let name = name.unwrap_or_else(|| fail("No system or command name provided"));
 
-            let mut buffer = [0; 25];
-            let len = daemon.read(&mut buffer).expect("Could not read from socket");
+            let listing = list();
+            let system = listing.systems.iter().find(|system| system.name == name);
+            let command = listing.commands.iter().find(|command| command.name == name);
+            if system.is_none() && command.is_none() {
+                fail(format!("No system or command named '{}'", name));
+            }
 
-            let string = String::from_utf8_lossy(&buffer.to_vec().as_slice()[..len]).to_string();
+            // A system and a command can share a name, so JSON always has room for both.
+            if json {
+                print_json(&serde_json::json!({ "system": system, "command": command }));
+                return;
+            }
+            if let Some(system) = system {
+                println!("{}", system);
+            }
+            if system.is_some() && command.is_some() {
+                println!();
+            }
+            if let Some(command) = command {
+                println!("{}", command);
+            }
+        }
+        "status" => {
+            let json = match args.next().as_deref() {
+                Some("--json") => true,
+                Some(other) => fail(format!("Unknown argument '{}'; expected --json", other)),
+                None => false,
+            };
+            match request(Request::Status) {
+                Response::Status(status) if json => print_json(&status),
+                Response::Status(status) => println!("{}", status),
+                other => fail(ProtocolError::Unexpected(Box::new(other))),
+            }
+        }
+        "--reload" => {
+            match request(Request::Reload) {
+                Response::Reloaded(summary) => {
+                    println!("Reloaded!");
+                    println!("Systems:  {}", summary.systems);
+                    println!("Commands: {}", summary.commands);
+                }
+                other => fail(ProtocolError::Unexpected(Box::new(other))),
+            }
+        }
+        "--flush" => {
+            let mut daemon = connect();
+            // A big queue can take a while to write out.
+            daemon
+                .stream
+                .set_read_timeout(None)
+                .expect("Could not clear read timeout");
 
-            if string != "pong" {
-                panic!("Could not ping daemon got {}", string);
-            } else {
-                println!("Pong!");
+            match daemon.request(&Request::Flush) {
+                Ok(Response::Flushed(report)) => println!("Flushed {}.", report),
+                Ok(Response::Error(e)) => fail(e),
+                Ok(other) => fail(ProtocolError::Unexpected(Box::new(other))),
+                Err(e) => fail(format!("Daemon request failed: {}", e)),
             }
-            
         }
+        "start" => {
+            let system = args.next().expect("No system name provided");
+            request(Request::Start {
+                system: system.clone(),
+            });
+            println!("Started system '{}'.", system);
+        }
+        "stop" => {
+            let system = args.next().expect("No system name provided");
+            request(Request::Stop {
+                system: system.clone(),
+            });
+            println!("Stopping system '{}' after its current stage.", system);
+        }
+        "shutdown" => {
+            let mut daemon = connect();
+            // The daemon waits for running stages before it answers.
+            daemon
+                .stream
+                .set_read_timeout(None)
+                .expect("Could not clear read timeout");
+
+            match daemon.request(&Request::Shutdown) {
+                Ok(Response::ShuttingDown { killed }) if killed.is_empty() => {
+                    println!("Daemon shut down.");
+                }
+                Ok(Response::ShuttingDown { killed }) => {
+                    println!("Daemon shut down; killed {}.", killed.join(", "));
+                }
+                Ok(Response::Error(e)) => fail(e),
+                Ok(other) => fail(ProtocolError::Unexpected(Box::new(other))),
+                Err(e) => fail(format!("Daemon request failed: {}", e)),
+            }
+        }
+        "check" => {
+            let path = args
+                .next()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| paths.config.clone());
+            let parser = TomlParser::load(&path);
+            let problems = parser.check();
+            if !problems.is_empty() {
+                fail(problems);
+            }
+            for file in parser.files() {
+                println!("{}: no problems found.", file.display());
+            }
+        }
+        "--ping" => match request(Request::Ping) {
+            Response::Pong => println!("Pong!"),
+            other => fail(ProtocolError::Unexpected(Box::new(other))),
+        },
         _ => {
-            println!("Usage: toaster --reload | --flush | --ping");
+            println!("Usage: toaster [--config <file>] [--socket <path>] run <command> [args...] | help <command> | list [systems|commands] [--json] | describe <name> [--json] | status [--json] | start <system> | stop <system> | shutdown | logs [--system <name>] [--since <time>] [--until <time>] [--grep <text>] [--failed-only] [--tail <n>] [-f|--follow] | check [path] | --reload | --flush | --ping");
         }
     }
-
-    daemon.flush().expect("Could not flush socket");
 }
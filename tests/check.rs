@@ -0,0 +1,70 @@
+use std::{
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Runs `toaster check` on `content`, written to a file named after the test.
+fn check(name: &str, content: &str) -> Output {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let config = dir.join(format!("{}.toml", name));
+    std::fs::write(&config, content).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_toaster"))
+        .arg("check")
+        .arg(&config)
+        .env("TOASTER_HOME", &dir)
+        .output()
+        .unwrap()
+}
+
+fn assert_fails(output: &Output, problem: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "check passed: {}", stderr);
+    assert!(stderr.contains(problem), "{}", stderr);
+}
+
+#[test]
+fn a_clean_config_passes() {
+    let output = check(
+        "clean",
+        r#"
+        [settings]
+        threads = 2
+
+        [command.list]
+        stages = ["%[o:l3] ls"]
+        "#,
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn zero_threads_fail() {
+    let output = check("zero-threads", "[settings]\nthreads = 0\n");
+    assert_fails(
+        &output,
+        "zero-threads.toml:2: settings.threads: threads must be at least 1",
+    );
+}
+
+#[test]
+fn a_zero_default_row_length_fails() {
+    let output = check("zero-row-length", "[settings]\ndefault_row_length = 0\n");
+    assert_fails(
+        &output,
+        "settings.default_row_length: default_row_length must be at least 1",
+    );
+}
+
+#[test]
+fn a_zero_row_length_parameter_fails() {
+    let output = check(
+        "zero-row-parameter",
+        "[command.list]\nstages = [\"%[o:l0] ls\"]\n",
+    );
+    assert_fails(&output, "invalid row length '0' in 'o:l0'");
+}